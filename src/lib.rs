@@ -1,17 +1,25 @@
 #![feature(slice_patterns)]
 
+extern crate libc;
 extern crate num;
 extern crate rustyline;
 
 mod lisp_value;
 mod lisp_environment;
+mod reader;
+
+use std::cell::Cell;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-pub use lisp_value::{LispValue, LispNum};
+pub use lisp_value::{LispValue, LispNum, LispError, ErrorKind, SharedValue};
 pub use lisp_environment::LispEnvironment;
-pub use scheme::{expression, completeInput};
+pub use reader::{expression, program, read_program, read_program_with, completeInput, ReadError, ReadResult, SourceMap, Span};
 
 const HISTORY_FILE: &'static str = "history.txt";
 
@@ -19,8 +27,61 @@ mod scheme {
     include!(concat!(env!("OUT_DIR"), "/scheme.rs"));
 }
 
+/// Runs the file named on the command line, or else a REPL; `--expand` prints each expanded form.
 pub fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let expand = match args.iter().position(|arg| arg == "--expand") {
+        Some(i) => { args.remove(i); true },
+        None => false
+    };
+    unsafe { libc::signal(libc::SIGINT, interrupt as libc::sighandler_t); }
+
+    match args.first() {
+        Some(path) => run_file(path, expand),
+        None       => repl(expand)
+    }
+}
+
+fn show_expansion(form: &LispValue, world: &LispEnvironment) {
+    if let Ok(core) = world.expand(form) {
+        println!("=== {}", core);
+    }
+}
+
+fn run_file(path: &str, expand: bool) {
+    let mut source = String::new();
+    if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+        println!("Error: cannot read {}: {}", path, err);
+        process::exit(1);
+    }
+
+    let (forms, source_map) = match read_program(path, &source) {
+        Ok(read) => read,
+        Err(err) => {
+            println!("{}:{}", path, err);
+            process::exit(1);
+        }
+    };
+
     let mut world = LispEnvironment::default();
+    for form in forms {
+        if expand { show_expansion(&form, &world); }
+        let (result, new_world) = world.eval_source(&form, &source_map);
+        if let Err(err) = result {
+            println!("{}", source_map.report(&err));
+            process::exit(1);
+        }
+        world = new_world;
+    }
+}
+
+extern "C" fn interrupt(_: libc::c_int) {
+    LispEnvironment::interrupt();
+}
+
+fn repl(expand: bool) {
+    let mut world = LispEnvironment::default();
+    let fold_case = Cell::new(false);
     let mut rl = Editor::<()>::new();
 
     if rl.load_history(HISTORY_FILE).is_err() {
@@ -46,7 +107,7 @@ pub fn main() {
                         }
                     }
                 }
-                if let Some(new_world) = evaluate(line.trim(), &world) { world = new_world; }
+                if let Some(new_world) = evaluate(line.trim(), &world, &fold_case, expand) { world = new_world; }
             },
             Err(ReadlineError::Interrupted) => {},
             Err(ReadlineError::Eof) => break,
@@ -64,20 +125,30 @@ fn incomplete(line: &str) -> bool {
     completeInput(line).is_err()
 }
 
-fn evaluate(input: &str, world: &LispEnvironment) -> Option<LispEnvironment> {
-    match expression(input) {
-        Ok(ast)  => {
-            let (result, new_world) = ast.eval_in(world);
-            if cfg!(feature = "show_ast") { println!("{:?}", ast); }
-            match result {
-                Ok(cool) => {
-                    println!("<<< {}", cool);
-                    return Some(new_world);
+fn evaluate(input: &str, world: &LispEnvironment, fold_case: &Cell<bool>, expand: bool) -> Option<LispEnvironment> {
+    match read_program_with("<repl>", input, fold_case) {
+        Ok((forms, source_map)) => {
+            let mut world = world.clone();
+            for ast in forms {
+                if expand { show_expansion(&ast, &world); }
+                let (result, new_world) = world.eval_source(&ast, &source_map);
+                if cfg!(feature = "show_ast") { println!("{:?}", ast); }
+                match result {
+                    Ok(cool) => {
+                        println!("<<< {}", cool);
+                        world = new_world;
+                    }
+                    Err(or)  => {
+                        println!("ERR {}", source_map.report(&or));
+                        break;
+                    }
                 }
-                Err(or)  => println!("ERR {}", or)
             }
+            Some(world)
         },
-        Err(err) => println!("{:?}", err),
+        Err(err) => {
+            println!("ERR <repl>:{}", err);
+            None
+        }
     }
-    None
 }
@@ -1,24 +1,39 @@
-use std::rc::Rc;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
+use std::iter;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering as AtomicOrdering};
 
-use num::{Zero, One};
-use super::lisp_value::{LispValue, LispResult, LispNum, LispFunction, PrimitiveFunction};
+use num::{Zero, One, ToPrimitive};
+use num::bigint::BigInt;
+use super::lisp_value::{LispValue, LispResult, LispError, ErrorKind, LispNum, LispFunction, PrimitiveFunction,
+                        SyntaxRules, Continuation};
+use super::lisp_value::syntax_rules::{is_alias, unalias, base_name, strip_syntax};
+use super::reader::{program, program_ci, SourceMap, Span};
 
 type LispVtable = HashMap<String, LispValue>;
 
+/// Set when the user interrupts evaluation, to be noticed at the next step.
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
 macro_rules! varargs {
     (nil) => (None);
     ($w:ident) => (Some(stringify!($w).into()));
 }
 
 macro_rules! lisp_funcs {
-    ($($name:expr => [$($arg:ident),*], $varargs:ident, $definition:expr);+ $(;)*) => ({
+    ($kind:ident; $($name:expr => [$($arg:ident),*], $varargs:ident, $definition:expr);+ $(;)*) => ({
         let mut env: LispVtable = HashMap::new();
         $(
             let name = $name;
             env.insert(name.into(), LispValue::PrimitiveFunction(
-                PrimitiveFunction::new(
+                PrimitiveFunction::$kind(
                     name,
                     &[$(stringify!($arg).into()),*],
                     varargs!($varargs),
@@ -29,24 +44,206 @@ macro_rules! lisp_funcs {
     });
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone)]
 pub struct LispEnvironment {
+    frame: Rc<RefCell<Frame>>,
+    expansions: Rc<Cell<usize>>
+}
+
+/// A reference to an environment that does not keep it alive.
+struct WeakEnvironment {
+    frame: Weak<RefCell<Frame>>,
+    expansions: Rc<Cell<usize>>
+}
+
+impl WeakEnvironment {
+    fn upgrade(&self) -> Option<LispEnvironment> {
+        self.frame.upgrade().map(|frame| LispEnvironment { frame: frame, expansions: self.expansions.clone() })
+    }
+}
+
+struct Frame {
     vtable: LispVtable,
+    uninitialised: HashSet<String>,
+    parent: Option<LispEnvironment>,
+    expansion: Option<(usize, LispEnvironment)>
+}
+
+/// What is left of evaluating a form, for `LispEnvironment::run` to carry on with.
+pub enum Step {
+    Done(LispResult),
+    Eval(LispValue, LispEnvironment),
+    Apply(LispValue, Vec<LispValue>),
+    Then(Box<Step>, Pending),
+    CallCC(LispValue),
+    Wind(LispValue, LispValue, LispValue),
+    Rewind(Winders, Box<Step>),
+    WithHandler(LispValue, LispValue),
+    Handle(Handlers, Box<Step>),
+    Raise(LispValue, bool)
+}
+
+pub type Pending = Rc<Fn(LispValue) -> Step>;
+
+/// The pending work of a computation, innermost last, with where in the source each waits.
+pub type Stack = Vec<(Pending, Option<Span>)>;
+
+pub type Winders = Option<Rc<Wind>>;
+
+pub struct Wind {
+    before: LispValue,
+    after: LispValue,
+    parent: Winders
+}
+
+pub type Handlers = Option<Rc<Handler>>;
+
+pub struct Handler {
+    procedure: LispValue,
+    outer: Handlers
+}
+
+struct Iteration {
+    vars: Vec<String>,
+    test: LispValue,
+    results: Vec<LispValue>,
+    commands: usize,
+    updates: Rc<Vec<LispValue>>
 }
 
 impl LispEnvironment {
-    pub fn call(&self, list: &[LispValue]) -> (LispResult, LispEnvironment) {
-        let mut new_world = self.clone();
+    fn new(vtable: LispVtable, parent: Option<LispEnvironment>) -> LispEnvironment {
+        let expansions = match parent {
+            Some(ref parent) => parent.expansions.clone(),
+            None => Rc::new(Cell::new(0))
+        };
+
+        LispEnvironment {
+            frame: Rc::new(RefCell::new(Frame {
+                vtable: vtable,
+                uninitialised: HashSet::new(),
+                parent: parent,
+                expansion: None
+            })),
+            expansions: expansions
+        }
+    }
+
+    fn downgrade(&self) -> WeakEnvironment {
+        WeakEnvironment { frame: Rc::downgrade(&self.frame), expansions: self.expansions.clone() }
+    }
+
+    pub fn extend(&self) -> LispEnvironment {
+        LispEnvironment::new(HashMap::new(), Some(self.clone()))
+    }
+
+    /// Evaluates `expr` from the top level, dropping any stale interrupt.
+    pub fn eval(&self, expr: &LispValue) -> (LispResult, LispEnvironment) {
+        self.eval_from(expr, None)
+    }
+
+    /// Evaluates `expr` like `eval`, locating errors by where `source` read its forms.
+    pub fn eval_source(&self, expr: &LispValue, source: &SourceMap) -> (LispResult, LispEnvironment) {
+        self.eval_from(expr, Some(source))
+    }
+
+    fn eval_from(&self, expr: &LispValue, source: Option<&SourceMap>) -> (LispResult, LispEnvironment) {
+        INTERRUPTED.store(false, AtomicOrdering::SeqCst);
+        (LispEnvironment::run(Step::Eval(expr.clone(), self.clone()), source), self.clone())
+    }
+
+    /// Carries `step` through to a result, attaching any error to the last located form evaluated.
+    fn run(mut step: Step, source: Option<&SourceMap>) -> LispResult {
+        let mut span = None;
+        let mut stack: Stack = vec![];
+        let mut winders: Winders = None;
+        let mut handlers: Handlers = None;
+
+        loop {
+            step = match step {
+                Step::Eval(..) if INTERRUPTED.load(AtomicOrdering::Relaxed) &&
+                    INTERRUPTED.swap(false, AtomicOrdering::SeqCst) =>
+                    Step::Done(Err(LispError::of_kind("Interrupted", ErrorKind::Interrupt))),
+                Step::Eval(expr, world) => {
+                    let next = match expr {
+                        LispValue::List(ref items) |
+                        LispValue::DottedList(ref items, _) => world.step(items),
+                        LispValue::Atom(ref name) => Step::Done(world.get(name)),
+                        LispValue::Shared(ref value) => Step::Eval(value.get(), world),
+                        _ => Step::Done(Ok(expr.clone()))
+                    };
+                    if let Some(found) = source.and_then(|source| source.locate(&expr)) {
+                        span = Some(found);
+                    }
+                    next
+                },
+                Step::Apply(LispValue::Continuation(ref k), ref args) if args.len() <= 1 => {
+                    let value = args.first().cloned().unwrap_or_else(|| LispValue::list(vec![]));
+                    stack = k.stack();
+                    handlers = k.handlers().clone();
+                    travel(&winders, k.winders(), Ok(value))
+                },
+                Step::Apply(function, args) => apply(&function, &args),
+                Step::Then(step, rest) => {
+                    stack.push((rest, span));
+                    *step
+                },
+                Step::CallCC(receiver) => {
+                    let k = LispValue::Continuation(Continuation::new(stack.clone(), winders.clone(), handlers.clone()));
+                    Step::Apply(receiver, vec![k])
+                },
+                Step::Wind(before, thunk, after) => wind(before, thunk, after, &winders),
+                Step::Rewind(to, step) => {
+                    winders = to;
+                    *step
+                },
+                Step::WithHandler(handler, thunk) => with_handler(handler, thunk, &handlers),
+                Step::Handle(to, step) => {
+                    handlers = to;
+                    *step
+                },
+                Step::Raise(condition, continuable) => match handlers {
+                    Some(ref handler) => raise(handler, condition, continuable),
+                    None => Step::Done(Err(LispError::uncaught(condition)))
+                },
+                Step::Done(Ok(value)) => match stack.pop() {
+                    Some((rest, waiting)) => {
+                        span = waiting;
+                        rest(value)
+                    },
+                    None => return Ok(value)
+                },
+                Step::Done(Err(err)) => {
+                    let err = match span {
+                        Some(span) => err.at(span),
+                        None => err
+                    };
+                    if handlers.is_some() && err.kind != ErrorKind::Interrupt {
+                        Step::Raise(err.condition(), false)
+                    } else if winders.is_none() {
+                        return Err(err);
+                    } else {
+                        stack.clear();
+                        handlers = None;
+                        travel(&winders, &None, Err(err))
+                    }
+                }
+            };
+        }
+    }
+
+    fn step(&self, list: &[LispValue]) -> Step {
         let result = match *list {
             [LispValue::Atom(ref f), ref args..] => {
-                match &f[..] {
+                let keyword = self.keyword(f);
+                match &keyword[..] {
                     "define" => match *args {
                         [LispValue::List(ref args), ref body..] => {
                             match args[..] {
                                 [LispValue::Atom(ref name), ref args..] => {
                                     let func = LispValue::Function(
-                                        LispFunction::new(&new_world, args, body));
-                                    new_world.set(name, func.clone());
+                                        LispFunction::new(self, args, body));
+                                    self.define(name, func.clone());
                                     Ok(func)
                                 },
                                 _ => Err("Invalid function definition".into())
@@ -56,198 +253,1152 @@ impl LispEnvironment {
                             match args[..] {
                                 [LispValue::Atom(ref name), ref args..] => {
                                     let func = LispValue::Function(LispFunction::new_with_varargs(
-                                        &new_world, args, *varargs.clone(), body));
-                                    new_world.set(name, func.clone());
+                                        self, args, *varargs.clone(), body));
+                                    self.define(name, func.clone());
                                     Ok(func)
                                 },
                                 _ => Err("Invalid varargs function definition".into())
                             }
                         },
                         [LispValue::Atom(ref name), ref value] => {
-                            let val = match *value {
-                                LispValue::Atom(ref x) => {
-                                    let y = self.get(x);
-                                    if let Ok(z) = y {
-                                        z
-                                    } else {
-                                        return (y, new_world);
-                                    }
-                                },
-                                LispValue::List(ref f) |
-                                LispValue::DottedList(ref f, _) => {
-                                    let y = self.call(f).0;
-                                    if let Ok(z) = y {
-                                        z
-                                    } else {
-                                        return (y, new_world);
-                                    }
-                                }
-                                _ => value.clone()
-                            };
-                            new_world.set(name, val.clone());
-                            Ok(val.clone())
+                            let (env, name) = (self.clone(), name.clone());
+                            return self.evaluate(value, move |value| {
+                                env.define(&name, value.clone());
+                                Step::Done(Ok(value))
+                            });
                         },
                         _ => Err("Invalid definition".into())
                     },
                     "set!" => match *args {
                         [LispValue::Atom(ref name), ref value] => {
-                            if new_world.defined(name) {
-                                let val = match *value {
-                                    LispValue::Atom(ref x) => {
-                                        let y = self.get(x);
-                                        if let Ok(z) = y {
-                                            z
-                                        } else {
-                                            return (y, new_world);
-                                        }
-                                    },
-                                    LispValue::List(ref f) |
-                                    LispValue::DottedList(ref f, _) => {
-                                        let y = self.call(f).0;
-                                        if let Ok(z) = y {
-                                            z
-                                        } else {
-                                            return (y, new_world);
-                                        }
-                                    }
-                                    _ => value.clone()
-                                };
-                                new_world.set(name, val.clone());
-                                Ok(val.clone())
-                            } else {
-                                Err(format!("Undefined variable: '{}'", name))
-                            }
+                            let (env, name) = (self.clone(), name.clone());
+                            return self.evaluate(value, move |value| {
+                                Step::Done(env.assign(&name, value.clone()).map(|_| value))
+                            });
                         },
                         _ => Err("Invalid set!".into())
                     },
                     "lambda" => match *args {
                         [LispValue::List(ref args), ref body..] =>
-                            Ok(LispValue::Function(LispFunction::new(
-                                &new_world, args, body))),
+                            Ok(LispValue::Function(LispFunction::new(self, args, body))),
                         [LispValue::DottedList(ref args, ref varargs), ref body..] =>
                             Ok(LispValue::Function(LispFunction::new_with_varargs(
-                                &new_world, args, *varargs.clone(), body))),
+                                self, args, *varargs.clone(), body))),
                         [ref varargs, ref body..] =>
                             Ok(LispValue::Function(LispFunction::new_with_varargs(
-                                &new_world, &[], varargs.clone(), body))),
+                                self, &[], varargs.clone(), body))),
                         _ => Err("Invalid lambda".into())
                     },
-                    "quote" => Ok(args[0].clone()),
+                    "quote" => match *args {
+                        [ref datum] => Ok(strip_syntax(datum)),
+                        _ => Err("Bad 'quote'".into())
+                    },
+                    "quasiquote" => match *args {
+                        [ref template] => return self.quasiquote_form(template),
+                        _ => Err("Invalid quasiquote".into())
+                    },
                     "if" => match *args {
-                        [ref predicate, ref consequent, ref alternate] => {
-                            let (result, tmp_world) = predicate.eval_in(self);
-                            let branch = match result {
-                                Ok(LispValue::Boolean(false)) => alternate,
-                                Ok(_)                         => consequent,
-                                Err(_)                        => return (result, tmp_world)
-                            };
-
-                            return branch.eval_in(&tmp_world);
+                        [ref predicate, ref consequent, ref alternate..] if alternate.len() <= 1 => {
+                            let env = self.clone();
+                            let (consequent, alternate) = (consequent.clone(), alternate.first().cloned());
+                            return self.evaluate(predicate, move |value| {
+                                let branch = if is_true(&value) { Some(&consequent) } else { alternate.as_ref() };
+                                match branch {
+                                    Some(branch) => Step::Eval(branch.clone(), env.clone()),
+                                    None => Step::Done(Ok(LispValue::list(vec![])))
+                                }
+                            });
                         },
                         _ => Err("Bad 'if'".into())
                     },
-                    _ => match self.vtable.get(f) {
-                        Some(&LispValue::PrimitiveFunction(ref f)) => {
-                            f.check_arity(args)
-                                .and_then(|args| self.eval_args(&args))
-                                .and_then(|args| f.call(&new_world, &args))
+                    "cond" => return self.cond(args),
+                    "case" => match args.split_first() {
+                        Some((key, clauses)) => {
+                            let (env, clauses) = (self.clone(), clauses.to_vec());
+                            return self.evaluate(key, move |key| env.case(&clauses, key));
                         },
-                        Some(&LispValue::Function(ref f)) =>
-                            f.check_arity(args)
-                                .and_then(|args| self.eval_args(&args))
-                                .and_then(|args| f.call(&new_world, &args)),
-                        Some(&ref x) => Err(format!("No such function: {}", x)),
-                        None => Err(format!("No such function: {}", f))
+                        None => Err("Bad 'case'".into())
+                    },
+                    "and" | "or" => return self.logic(keyword == "or", args),
+                    "when" | "unless" => match *args {
+                        [ref test, ref body..] => {
+                            let (env, body, polarity) = (self.clone(), body.to_vec(), keyword == "when");
+                            return self.evaluate(test, move |value| if is_true(&value) == polarity {
+                                env.eval_sequence(&body)
+                            } else {
+                                Step::Done(Ok(LispValue::list(vec![])))
+                            });
+                        },
+                        _ => Err(format!("Bad '{}'", keyword).into())
+                    },
+                    "let" | "let*" | "letrec" | "letrec*" => return self.let_form(&keyword, args),
+                    "begin" => return self.eval_sequence(args),
+                    "do" => return self.do_loop(args),
+                    "define-syntax" => match *args {
+                        [LispValue::Atom(ref name), ref spec] => self.transformer(spec, self).map(|macro_| {
+                            self.define(name, LispValue::Macro(macro_));
+                            LispValue::list(vec![])
+                        }),
+                        _ => Err("Bad 'define-syntax'".into())
+                    },
+                    "let-syntax" | "letrec-syntax" => return self.let_syntax(&keyword, args),
+                    "guard" => return self.guard(args),
+                    "include" => return self.include(args, false),
+                    "include-ci" => return self.include(args, true),
+                    _ => match self.get(f) {
+                        Ok(LispValue::Macro(ref m)) => match m.expand(list, self) {
+                            Ok((expansion, scope)) => return Step::Eval(expansion, scope),
+                            Err(err) => Err(err)
+                        },
+                        Ok(procedure @ LispValue::PrimitiveFunction(_)) |
+                        Ok(procedure @ LispValue::Function(_)) |
+                        Ok(procedure @ LispValue::Continuation(_)) => return self.call(procedure, args),
+                        Ok(ref x) => Err(format!("No such function: {}", x).into()),
+                        Err(_) => Err(format!("No such function: {}", base_name(f)).into())
                     }
                 }
             },
-            [LispValue::PrimitiveFunction(ref f), ref args..] =>
-                f.check_arity(args)
-                    .and_then(|args| self.eval_args(&args))
-                    .and_then(|args| f.call(&new_world, &args)),
-            [LispValue::Function(ref f), ref args..] =>
-                f.check_arity(args)
-                    .and_then(|args| self.eval_args(&args))
-                    .and_then(|args| f.call(&new_world, &args)),
-            [LispValue::List(ref f), ref args..] |
-            [LispValue::DottedList(ref f, _), ref args..] => {
-                let val = new_world.call(f).0;
-                if let Ok(value) = val {
-                    let mut new_list = vec![value];
-                    new_list.extend_from_slice(args);
-                    return new_world.call(&new_list);
-                } else {
-                    val
-                }
+            [ref procedure @ LispValue::PrimitiveFunction(_), ref args..] |
+            [ref procedure @ LispValue::Function(_), ref args..] |
+            [ref procedure @ LispValue::Continuation(_), ref args..] =>
+                return self.call(procedure.clone(), args),
+            [LispValue::List(_), ..] |
+            [LispValue::DottedList(..), ..] =>
+                return self.evaluate_all(Rc::new(list.to_vec()), vec![], Rc::new(|mut values: Vec<LispValue>| {
+                    let procedure = values.remove(0);
+                    Step::Apply(procedure, values)
+                })),
+            [ref f, ..] => Err(format!("{} is not a function.", f).into()),
+            [] => Ok(LispValue::list(vec![]))
+        };
+        Step::Done(result)
+    }
+
+    /// Stops the evaluation in progress at its next form; safe in a signal handler.
+    pub fn interrupt() {
+        INTERRUPTED.store(true, AtomicOrdering::SeqCst);
+    }
+
+    fn evaluate<F>(&self, expr: &LispValue, rest: F) -> Step
+        where F: Fn(LispValue) -> Step + 'static {
+        match *expr {
+            LispValue::Atom(ref name) => match self.get(name) {
+                Ok(value) => rest(value),
+                Err(err) => Step::Done(Err(err))
             },
-            [ref f, ..] => Err(format!("{} is not a function.", f)),
-            [] => Ok(LispValue::List(vec![]))
+            LispValue::List(_) | LispValue::DottedList(..) | LispValue::Shared(_) =>
+                then(Step::Eval(expr.clone(), self.clone()), rest),
+            _ => rest(expr.clone())
+        }
+    }
+
+    fn evaluate_all(&self, exprs: Rc<Vec<LispValue>>, values: Vec<LispValue>,
+                    rest: Rc<Fn(Vec<LispValue>) -> Step>) -> Step {
+        let expr = match exprs.get(values.len()) {
+            Some(expr) => expr.clone(),
+            None => return rest(values)
         };
-        (result, new_world)
+        let env = self.clone();
+
+        self.evaluate(&expr, move |value| {
+            let mut values = values.clone();
+            values.push(value);
+            env.evaluate_all(exprs.clone(), values, rest.clone())
+        })
     }
 
-    pub fn eval_many(&self, forms: &[LispValue]) -> (LispResult, LispEnvironment) {
-        let mut world = self.clone();
-        let mut result = Ok(LispValue::List(vec![]));
+    fn call(&self, procedure: LispValue, args: &[LispValue]) -> Step {
+        self.evaluate_all(Rc::new(args.to_vec()), vec![], Rc::new(move |args| {
+            Step::Apply(procedure.clone(), args)
+        }))
+    }
 
-        for form in forms {
-            let (new_result, new_world) = form.eval_in(&world);
-            world = new_world;
-            if new_result.is_ok() {
-                result = new_result;
-            } else {
-                return (new_result, world);
+    pub fn eval_body(&self, body: &[LispValue]) -> Step {
+        for form in body {
+            for name in self.definition_names(form) {
+                self.declare(&name);
+            }
+        }
+
+        self.eval_sequence(body)
+    }
+
+    fn eval_sequence(&self, forms: &[LispValue]) -> Step {
+        match forms.len() {
+            0 => Step::Done(Ok(LispValue::list(vec![]))),
+            1 => Step::Eval(forms[0].clone(), self.clone()),
+            _ => self.sequence(Rc::new(forms.to_vec()), 0)
+        }
+    }
+
+    fn sequence(&self, forms: Rc<Vec<LispValue>>, next: usize) -> Step {
+        let form = forms[next].clone();
+        if next + 1 == forms.len() {
+            return Step::Eval(form, self.clone());
+        }
+        let env = self.clone();
+
+        self.evaluate(&form, move |_| env.sequence(forms.clone(), next + 1))
+    }
+
+    fn clause_body(&self, body: &[LispValue], value: LispValue) -> Step {
+        match *body {
+            [ref arrow, ref receiver] if self.is_keyword(arrow, "=>") =>
+                Step::Eval(LispValue::list(vec![receiver.clone(), LispValue::quote(value)]), self.clone()),
+            [] => Step::Done(Ok(value)),
+            _ => self.eval_sequence(body)
+        }
+    }
+
+    fn cond(&self, clauses: &[LispValue]) -> Step {
+        let (clause, rest) = match clauses.split_first() {
+            Some(clauses) => clauses,
+            None => return Step::Done(Ok(LispValue::list(vec![])))
+        };
+        let (test, body) = match *clause {
+            LispValue::List(ref clause) if !clause.is_empty() => (&clause[0], &clause[1..]),
+            _ => return Step::Done(Err("Bad 'cond'".into()))
+        };
+        if self.is_keyword(test, "else") {
+            return self.eval_sequence(body);
+        }
+        let (env, body, rest) = (self.clone(), body.to_vec(), rest.to_vec());
+
+        self.evaluate(test, move |value| if is_true(&value) {
+            env.clause_body(&body, value)
+        } else {
+            env.cond(&rest)
+        })
+    }
+
+    fn case(&self, clauses: &[LispValue], key: LispValue) -> Step {
+        for clause in clauses {
+            let (data, body) = match *clause {
+                LispValue::List(ref clause) if !clause.is_empty() => (&clause[0], &clause[1..]),
+                _ => return Step::Done(Err("Bad 'case'".into()))
+            };
+            let matches = match *data {
+                _ if self.is_keyword(data, "else") => true,
+                LispValue::List(ref data) => data.iter().any(|datum| is_eqv(&strip_syntax(datum), &key)),
+                _ => return Step::Done(Err("Bad 'case'".into()))
+            };
+            if matches {
+                return self.clause_body(body, key);
+            }
+        }
+
+        Step::Done(Ok(LispValue::list(vec![])))
+    }
+
+    fn logic(&self, deciding: bool, tests: &[LispValue]) -> Step {
+        let (test, rest) = match tests.split_first() {
+            Some(tests) => tests,
+            None => return Step::Done(Ok(LispValue::Boolean(!deciding)))
+        };
+        if rest.is_empty() {
+            return Step::Eval(test.clone(), self.clone());
+        }
+        let (env, rest) = (self.clone(), rest.to_vec());
+
+        self.evaluate(test, move |value| if is_true(&value) == deciding {
+            Step::Done(Ok(value))
+        } else {
+            env.logic(deciding, &rest)
+        })
+    }
+
+    fn let_form(&self, keyword: &str, args: &[LispValue]) -> Step {
+        let bad = || Step::Done(Err(format!("Bad '{}'", keyword).into()));
+        let (name, bindings, body) = match *args {
+            [LispValue::Atom(ref name), ref bindings, ref body..] if keyword == "let" =>
+                (Some(name), bindings, body),
+            [ref bindings, ref body..] => (None, bindings, body),
+            _ => return bad()
+        };
+        if body.is_empty() {
+            return bad();
+        }
+        let bindings = match let_bindings(bindings) {
+            Ok(bindings) => bindings,
+            Err(err) => return Step::Done(Err(err))
+        };
+
+        let env = self.extend();
+        match keyword {
+            "let*" => return self.sequential(&bindings, true, body),
+            "letrec" | "letrec*" => for &(ref var, _) in &bindings {
+                env.declare(var);
+            },
+            _ => {}
+        }
+        if keyword == "letrec*" {
+            return env.sequential(&bindings, false, body);
+        }
+
+        let (vars, inits): (Vec<String>, Vec<LispValue>) = bindings.into_iter().unzip();
+        if let Some(name) = name {
+            let params: Vec<_> = vars.into_iter().map(LispValue::Atom).collect();
+            let procedure = LispValue::Function(LispFunction::new(&env, &params, body));
+            env.define(name, procedure.clone());
+            return self.call(procedure, &inits);
+        }
+
+        let scope = if keyword == "letrec" { env.clone() } else { self.clone() };
+        let body = body.to_vec();
+        scope.evaluate_all(Rc::new(inits), vec![], Rc::new(move |values| {
+            for (var, value) in vars.iter().zip(values) {
+                env.define(var, value);
+            }
+            env.eval_body(&body)
+        }))
+    }
+
+    fn sequential(&self, bindings: &[(String, LispValue)], nested: bool, body: &[LispValue]) -> Step {
+        let (&(ref var, ref init), rest) = match bindings.split_first() {
+            Some(bindings) => bindings,
+            None => return if nested { self.extend().eval_body(body) } else { self.eval_body(body) }
+        };
+        let (env, var, rest, body) = (self.clone(), var.clone(), rest.to_vec(), body.to_vec());
+
+        self.evaluate(init, move |value| {
+            let env = if nested { env.extend() } else { env.clone() };
+            env.define(&var, value);
+            env.sequential(&rest, nested, &body)
+        })
+    }
+
+    /// `(do ((var init step)...) (test result...) command...)`, binding afresh each round.
+    fn do_loop(&self, args: &[LispValue]) -> Step {
+        let bad = || Step::Done(Err("Bad 'do'".into()));
+        let (specs, test, results, commands) = match *args {
+            [LispValue::List(ref specs), LispValue::List(ref exit), ref commands..] if !exit.is_empty() =>
+                (specs, &exit[0], &exit[1..], commands),
+            _ => return bad()
+        };
+
+        let mut vars = vec![];
+        let mut inits = vec![];
+        let mut updates = commands.to_vec();
+        for spec in specs.iter() {
+            match *spec {
+                LispValue::List(ref parts) => match parts[..] {
+                    [LispValue::Atom(ref var), ref init, ref step..] if step.len() <= 1 => {
+                        vars.push(var.clone());
+                        inits.push(init.clone());
+                        updates.push(step.first().cloned().unwrap_or_else(|| LispValue::Atom(var.clone())));
+                    },
+                    _ => return bad()
+                },
+                _ => return bad()
             }
+        }
+
+        let iteration = Rc::new(Iteration {
+            vars: vars,
+            test: test.clone(),
+            results: results.to_vec(),
+            commands: commands.len(),
+            updates: Rc::new(updates)
+        });
+        let env = self.clone();
+        self.evaluate_all(Rc::new(inits), vec![], Rc::new(move |values| env.do_round(iteration.clone(), values)))
+    }
+
+    fn do_round(&self, iteration: Rc<Iteration>, values: Vec<LispValue>) -> Step {
+        let env = self.extend();
+        for (var, value) in iteration.vars.iter().zip(values) {
+            env.define(var, value);
+        }
+        let (outer, round) = (self.clone(), env.clone());
+
+        env.evaluate(&iteration.test.clone(), move |value| {
+            if is_true(&value) {
+                return round.eval_sequence(&iteration.results);
+            }
+            let (outer, iteration) = (outer.clone(), iteration.clone());
+            round.evaluate_all(iteration.updates.clone(), vec![], Rc::new(move |values| {
+                outer.do_round(iteration.clone(), values[iteration.commands..].to_vec())
+            }))
+        })
+    }
+
+    fn guard(&self, args: &[LispValue]) -> Step {
+        let (var, clauses, body) = match *args {
+            [LispValue::List(ref spec), ref body..] => match spec.split_first() {
+                Some((&LispValue::Atom(ref var), clauses)) => (var.clone(), clauses.to_vec(), body),
+                _ => return Step::Done(Err("Bad 'guard'".into()))
+            },
+            _ => return Step::Done(Err("Bad 'guard'".into()))
         };
+        let body = LispValue::Function(LispFunction::new(self, &[], body));
+        let env = self.clone();
+
+        let receiver = procedure(&["k"], move |args| {
+            let guard_k = args[0].clone();
+            let handler = env.guard_handler(guard_k.clone(), var.clone(), clauses.clone());
+            then(Step::WithHandler(handler, body.clone()), move |value| {
+                Step::Apply(guard_k.clone(), vec![constant(value)])
+            })
+        });
+        then(Step::CallCC(receiver), |thunk| Step::Apply(thunk, vec![]))
+    }
+
+    /// The handler a `guard` installs, which passes `guard_k` a thunk choosing a clause.
+    fn guard_handler(&self, guard_k: LispValue, var: String, clauses: Vec<LispValue>) -> LispValue {
+        let env = self.clone();
+
+        procedure(&["condition"], move |args| {
+            let condition = args[0].clone();
+            let (guard_k, env, var, clauses) = (guard_k.clone(), env.clone(), var.clone(), clauses.clone());
+            let receiver = procedure(&["k"], move |args| {
+                let raise_again = procedure(&[], {
+                    let condition = condition.clone();
+                    move |_| Step::Raise(condition.clone(), true)
+                });
+                let otherwise = LispValue::list(vec![
+                    LispValue::Atom("else".into()),
+                    LispValue::list(vec![args[0].clone(), raise_again])
+                ]);
+                let clauses: Vec<_> = clauses.iter().cloned().chain(iter::once(otherwise)).collect();
+                let (env, var, condition) = (env.clone(), var.clone(), condition.clone());
+                let choose = procedure(&[], move |_| {
+                    let scope = env.extend();
+                    scope.define(&var, condition.clone());
+                    scope.cond(&clauses)
+                });
+                Step::Apply(guard_k.clone(), vec![choose])
+            });
+            then(Step::CallCC(receiver), |thunk| Step::Apply(thunk, vec![]))
+        })
+    }
 
-        (result, world)
+    fn include(&self, paths: &[LispValue], fold_case: bool) -> Step {
+        let mut forms = vec![];
+        for path in paths {
+            match read_file(path, fold_case) {
+                Ok(read) => forms.extend(read),
+                Err(err) => return Step::Done(Err(err))
+            }
+        }
+
+        self.eval_sequence(&forms)
     }
 
     pub fn get(&self, identifier: &str) -> LispResult {
-        match self.vtable.get(identifier) {
-            Some(val) => Ok(val.clone()),
-            None      => Err(format!("Undefined variable: '{}'!", identifier))
+        let frame = self.frame.borrow();
+        if frame.uninitialised.contains(identifier) {
+            return Err(uninitialised(identifier));
+        }
+        if let Some(val) = frame.vtable.get(identifier) {
+            return Ok(val.clone());
+        }
+        match (self.renamed(identifier), &frame.parent) {
+            (Some((name, env)), _)   => env.get(name),
+            (None, &Some(ref parent)) => parent.get(identifier),
+            (None, &None)            => Err(format!("Undefined variable: '{}'!", identifier).into())
+        }
+    }
+
+    pub fn define(&self, name: &str, value: LispValue) {
+        let env = self.defining();
+        let mut frame = env.frame.borrow_mut();
+        frame.uninitialised.remove(name);
+        frame.vtable.insert(name.into(), value);
+    }
+
+    /// Reserves `name` in the innermost frame for a later `define`.
+    pub fn declare(&self, name: &str) {
+        let env = self.defining();
+        let mut frame = env.frame.borrow_mut();
+        frame.vtable.remove(name);
+        frame.uninitialised.insert(name.into());
+    }
+
+    fn binds(&self, name: &str) -> bool {
+        let frame = self.frame.borrow();
+        frame.vtable.contains_key(name) || frame.uninitialised.contains(name)
+    }
+
+    /// The innermost frame that is not the scope of a macro expansion.
+    fn defining(&self) -> LispEnvironment {
+        match *self.frame.borrow() {
+            Frame { expansion: Some(_), parent: Some(ref parent), .. } => parent.defining(),
+            _ => self.clone()
+        }
+    }
+
+    pub fn assign(&self, name: &str, value: LispValue) -> Result<(), LispError> {
+        let parent = {
+            let mut frame = self.frame.borrow_mut();
+            if frame.uninitialised.contains(name) {
+                return Err(uninitialised(name));
+            }
+            if let Some(binding) = frame.vtable.get_mut(name) {
+                *binding = value;
+                return Ok(());
+            }
+            frame.parent.clone()
+        };
+        match (self.renamed(name), parent) {
+            (Some((original, env)), _) => env.assign(original, value),
+            (None, Some(parent))       => parent.assign(name, value),
+            (None, None)               => Err(format!("Undefined variable: '{}'", name).into())
+        }
+    }
+
+    /// Numbers an expansion by a macro from `definition`, giving the scope to evaluate it in.
+    pub fn begin_expansion(&self, definition: &LispEnvironment) -> (usize, LispEnvironment) {
+        let expansion = self.expansions.get();
+        self.expansions.set(expansion + 1);
+
+        let scope = self.extend();
+        scope.frame.borrow_mut().expansion = Some((expansion, definition.clone()));
+        (expansion, scope)
+    }
+
+    /// For a free alias from this frame's expansion, or any alias at the top, what it renames.
+    fn renamed<'a>(&self, name: &'a str) -> Option<(&'a str, LispEnvironment)> {
+        let frame = self.frame.borrow();
+        match (unalias(name), &frame.expansion, &frame.parent) {
+            (Some((original, expansion)), &Some((scope, ref env)), _)
+                if expansion == scope && !self.defining().binds(name) => Some((original, env.clone())),
+            (Some((original, _)), _, &None) => Some((original, self.clone())),
+            _ => None
+        }
+    }
+
+    /// What `name` refers to here: the frame binding it, or the plain name it renames.
+    pub fn denotation(&self, name: &str) -> (Option<usize>, String) {
+        if self.binds(name) {
+            return (Some(&*self.frame as *const RefCell<Frame> as usize), name.into());
+        }
+        let frame = self.frame.borrow();
+        match (self.renamed(name), &frame.parent) {
+            (Some((original, env)), _)   => env.denotation(original),
+            (None, &Some(ref parent)) => parent.denotation(name),
+            (None, &None)            => (None, name.into())
+        }
+    }
+
+    /// The special form `name` stands for, seeing through unbound aliases.
+    fn keyword<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if !is_alias(name) {
+            return Cow::Borrowed(name);
+        }
+        match self.denotation(name) {
+            (None, keyword) => Cow::Owned(keyword),
+            _ => Cow::Borrowed(name)
+        }
+    }
+
+    fn is_keyword(&self, form: &LispValue, keyword: &str) -> bool {
+        match *form {
+            LispValue::Atom(ref name) => self.keyword(name) == keyword,
+            _ => false
+        }
+    }
+
+    fn definition_names(&self, form: &LispValue) -> Vec<String> {
+        let target = match *form {
+            LispValue::List(ref items) => match items[..] {
+                [ref keyword, ref target, ..] if self.is_keyword(keyword, "define") => target,
+                [ref keyword, ref body..] if self.is_keyword(keyword, "begin") =>
+                    return body.iter().flat_map(|form| self.definition_names(form)).collect(),
+                _ => return vec![]
+            },
+            _ => return vec![]
+        };
+
+        match *target {
+            LispValue::Atom(ref name) => vec![name.clone()],
+            LispValue::List(ref signature) |
+            LispValue::DottedList(ref signature, _) => match signature.first() {
+                Some(&LispValue::Atom(ref name)) => vec![name.clone()],
+                _ => vec![]
+            },
+            _ => vec![]
+        }
+    }
+
+    fn transformer(&self, spec: &LispValue, env: &LispEnvironment) -> Result<SyntaxRules, LispError> {
+        match *spec {
+            LispValue::List(ref items) => match items[..] {
+                [ref keyword, ref rules..] if self.is_keyword(keyword, "syntax-rules") =>
+                    SyntaxRules::new(env, rules),
+                _ => Err(format!("Not a macro transformer: {}", spec).into())
+            },
+            LispValue::Atom(ref name) => match self.get(name) {
+                Ok(LispValue::Macro(macro_)) => Ok(macro_),
+                _ => Err(format!("Not a macro: {}", spec).into())
+            },
+            _ => Err(format!("Not a macro transformer: {}", spec).into())
+        }
+    }
+
+    fn let_syntax(&self, keyword: &str, args: &[LispValue]) -> Step {
+        let (bindings, body) = match *args {
+            [ref bindings, ref body..] => match let_bindings(bindings) {
+                Ok(bindings) => (bindings, body),
+                Err(err) => return Step::Done(Err(err))
+            },
+            _ => return Step::Done(Err(format!("Bad '{}'", keyword).into()))
+        };
+
+        let env = self.extend();
+        let scope = if keyword == "letrec-syntax" { &env } else { self };
+        for (name, spec) in bindings {
+            match self.transformer(&spec, scope) {
+                Ok(macro_) => env.define(&name, LispValue::Macro(macro_)),
+                Err(err) => return Step::Done(Err(err))
+            }
+        }
+        env.eval_body(body)
+    }
+
+    fn expand_once(&self, form: &LispValue) -> Result<Option<(LispValue, LispEnvironment)>, LispError> {
+        if let LispValue::List(ref items) = *form {
+            if let Some(&LispValue::Atom(ref name)) = items.first() {
+                if let Ok(LispValue::Macro(ref macro_)) = self.get(name) {
+                    return macro_.expand(items, self).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn macroexpand_1(&self, form: &LispValue) -> LispResult {
+        self.expand_once(form).map(|expanded| expanded.map_or_else(|| form.clone(), |(expansion, _)| expansion))
+    }
+
+    pub fn macroexpand(&self, form: &LispValue) -> LispResult {
+        let (mut form, mut env) = (form.clone(), self.clone());
+        while let Some((expansion, scope)) = try!(env.expand_once(&form)) {
+            form = expansion;
+            env = scope;
+        }
+        Ok(form)
+    }
+
+    pub fn expand(&self, form: &LispValue) -> LispResult {
+        self.expand_all(form).map(|core| self.unrename(&core))
+    }
+
+    fn expand_all(&self, form: &LispValue) -> LispResult {
+        let items = match *form {
+            LispValue::List(ref items) if !items.is_empty() => items,
+            _ => return Ok(form.clone())
+        };
+        let (head, args) = (&items[0], &items[1..]);
+        let name = match *head {
+            LispValue::Atom(ref name) => name,
+            _ => return self.expand_each(items).map(LispValue::list)
+        };
+        let keyword = self.keyword(name);
+        let rebuild = |parts: Vec<LispValue>| {
+            LispValue::list(iter::once(head.clone()).chain(parts).collect())
+        };
+
+        match &keyword[..] {
+            "quote" | "quasiquote" | "define-syntax" => Ok(form.clone()),
+            "lambda" if !args.is_empty() => {
+                let env = self.extend();
+                for param in parameter_names(&args[0]) {
+                    env.declare(&param);
+                }
+                env.expand_body(&args[1..]).map(|body| rebuild(iter::once(args[0].clone()).chain(body).collect()))
+            },
+            "define" => match *args {
+                [LispValue::Atom(_), ref value] =>
+                    self.expand_all(value).map(|value| rebuild(vec![args[0].clone(), value])),
+                [ref signature, ref body..] => {
+                    let env = self.extend();
+                    for param in parameter_names(signature).iter().skip(1) {
+                        env.declare(param);
+                    }
+                    env.expand_body(body).map(|body| rebuild(iter::once(signature.clone()).chain(body).collect()))
+                },
+                _ => Ok(form.clone())
+            },
+            "let" | "let*" | "letrec" | "letrec*" => {
+                let (name, bindings, body) = match *args {
+                    [ref name @ LispValue::Atom(_), ref bindings, ref body..] => (Some(name), bindings, body),
+                    [ref bindings, ref body..] => (None, bindings, body),
+                    _ => return Ok(form.clone())
+                };
+                let bindings = match let_bindings(bindings) {
+                    Ok(bindings) => bindings,
+                    Err(_) => return Ok(form.clone())
+                };
+                let env = self.extend();
+                for &(ref var, _) in &bindings {
+                    env.declare(var);
+                }
+                if let Some(&LispValue::Atom(ref name)) = name {
+                    env.declare(name);
+                }
+                let scope = if keyword == "let" { self } else { &env };
+
+                let mut parts: Vec<LispValue> = name.cloned().into_iter().collect();
+                let mut expanded = vec![];
+                for (var, init) in bindings {
+                    expanded.push(LispValue::list(vec![LispValue::Atom(var), try!(scope.expand_all(&init))]));
+                }
+                parts.push(LispValue::list(expanded));
+                parts.extend(try!(env.expand_body(body)));
+                Ok(rebuild(parts))
+            },
+            "do" => match *args {
+                [LispValue::List(ref specs), LispValue::List(ref exit), ref commands..] => {
+                    let env = self.extend();
+                    for var in binding_names(Some(&args[0])) {
+                        env.declare(&var);
+                    }
+                    let mut expanded = vec![];
+                    for spec in specs.iter() {
+                        expanded.push(match *spec {
+                            LispValue::List(ref parts) if parts.len() > 1 => {
+                                let mut parts = parts.to_vec();
+                                parts[1] = try!(self.expand_all(&parts[1]));
+                                for step in &mut parts[2..] {
+                                    *step = try!(env.expand_all(step));
+                                }
+                                LispValue::list(parts)
+                            },
+                            _ => spec.clone()
+                        });
+                    }
+                    let mut parts = vec![LispValue::list(expanded), LispValue::list(try!(env.expand_each(exit)))];
+                    parts.extend(try!(env.expand_each(commands)));
+                    Ok(rebuild(parts))
+                },
+                _ => Ok(form.clone())
+            },
+            "case" if !args.is_empty() => {
+                let mut parts = vec![try!(self.expand_all(&args[0]))];
+                for clause in &args[1..] {
+                    parts.push(match *clause {
+                        LispValue::List(ref clause) if !clause.is_empty() => {
+                            let body = try!(self.expand_each(&clause[1..]));
+                            LispValue::list(iter::once(clause[0].clone()).chain(body).collect())
+                        },
+                        _ => clause.clone()
+                    });
+                }
+                Ok(rebuild(parts))
+            },
+            "let-syntax" | "letrec-syntax" => match *args {
+                [ref bindings, ref body..] => {
+                    let env = self.extend();
+                    let scope = if keyword == "letrec-syntax" { &env } else { self };
+                    for (name, spec) in try!(let_bindings(bindings)) {
+                        env.define(&name, LispValue::Macro(try!(self.transformer(&spec, scope))));
+                    }
+                    let body = try!(env.expand_body(body));
+                    Ok(LispValue::list(vec![LispValue::Atom("let".into()), LispValue::list(vec![])]
+                        .into_iter()
+                        .chain(body)
+                        .collect()))
+                },
+                _ => Ok(form.clone())
+            },
+            _ => match self.get(name) {
+                Ok(LispValue::Macro(ref macro_)) =>
+                    macro_.expand(items, self).and_then(|(form, scope)| scope.expand_all(&form)),
+                _ => self.expand_each(items).map(LispValue::list)
+            }
         }
     }
 
-    pub fn set(&mut self, name: &str, value: LispValue) {
-        self.vtable.insert(name.into(), value);
+    fn expand_each(&self, forms: &[LispValue]) -> Result<Vec<LispValue>, LispError> {
+        forms.iter().map(|form| self.expand_all(form)).collect()
     }
 
-    pub fn merge(&self, other: &LispEnvironment) -> LispEnvironment {
-        let mut new = self.clone();
+    fn expand_body(&self, body: &[LispValue]) -> Result<Vec<LispValue>, LispError> {
+        for form in body {
+            for name in self.definition_names(form) {
+                self.declare(&name);
+            }
+        }
 
-        for (name, value) in other.clone().vtable {
-            new.set(&name, value.clone());
+        body.iter().map(|form| {
+            if let LispValue::List(ref items) = *form {
+                if let [ref keyword, LispValue::Atom(ref name), ref spec] = items[..] {
+                    if self.is_keyword(keyword, "define-syntax") {
+                        self.define(name, LispValue::Macro(try!(self.transformer(spec, self))));
+                    }
+                }
+            }
+            self.expand_all(form)
+        }).collect()
+    }
+
+    /// Turns aliases back into the names they rename, numbering bound ones like `tmp.3`.
+    fn unrename(&self, expansion: &LispValue) -> LispValue {
+        let mut bound = HashSet::new();
+        self.bound_aliases(expansion, &mut bound);
+        unrename(expansion, &bound)
+    }
+
+    fn bound_aliases(&self, form: &LispValue, bound: &mut HashSet<String>) {
+        let items = match *form {
+            LispValue::List(ref items) | LispValue::DottedList(ref items, _) => items,
+            _ => return
         };
 
-        new
+        if let Some(&LispValue::Atom(ref name)) = items.first() {
+            let names = match (&self.keyword(name)[..], items.get(1)) {
+                ("lambda", Some(target)) | ("define", Some(target)) | ("define-syntax", Some(target)) =>
+                    parameter_names(target),
+                ("let", Some(&LispValue::Atom(ref name))) =>
+                    iter::once(name.clone()).chain(binding_names(items.get(2))).collect(),
+                ("let", bindings) | ("let*", bindings) | ("letrec", bindings) | ("letrec*", bindings) |
+                ("let-syntax", bindings) | ("letrec-syntax", bindings) | ("do", bindings) =>
+                    binding_names(bindings),
+                _ => vec![]
+            };
+            bound.extend(names.into_iter().filter(|name| is_alias(name)));
+        }
+
+        for item in items.iter() {
+            self.bound_aliases(item, bound);
+        }
     }
 
-    fn defined(&self, name: &str) -> bool {
-        self.vtable.contains_key(name.into())
+    fn quasiquote_form(&self, template: &LispValue) -> Step {
+        let mut exprs = vec![];
+        if let Err(err) = self.quasiquote(template, 1, &mut |expr| {
+            exprs.push(expr.clone());
+            Ok(LispValue::list(vec![]))
+        }) {
+            return Step::Done(Err(err));
+        }
+        let (env, template) = (self.clone(), template.clone());
+
+        self.evaluate_all(Rc::new(exprs), vec![], Rc::new(move |values| {
+            let mut values = values.into_iter();
+            Step::Done(env.quasiquote(&template, 1, &mut |_| Ok(values.next().unwrap())))
+        }))
     }
 
-    fn eval_args(&self, args: &[LispValue]) -> Result<Vec<LispValue>, String> {
-        args.iter().map(|arg| arg.eval_in(self).0).collect()
+    fn quasiquote(&self, template: &LispValue, depth: usize,
+                  fill: &mut FnMut(&LispValue) -> LispResult) -> LispResult {
+        match *template {
+            LispValue::List(ref items) => match items[..] {
+                [ref keyword, ref expr] if self.is_keyword(keyword, "unquote") =>
+                    if depth == 1 {
+                        fill(expr)
+                    } else {
+                        self.quasiquote(expr, depth - 1, fill)
+                            .map(|expr| LispValue::abbreviation("unquote", expr))
+                    },
+                [ref keyword, ref expr] if self.is_keyword(keyword, "quasiquote") =>
+                    self.quasiquote(expr, depth + 1, fill)
+                        .map(|expr| LispValue::abbreviation("quasiquote", expr)),
+                _ => self.quasiquote_items(items, depth, fill).map(LispValue::list)
+            },
+            LispValue::DottedList(ref items, ref tail) => {
+                let mut items = try!(self.quasiquote_items(items, depth, fill));
+                match try!(self.quasiquote(tail, depth, fill)) {
+                    LispValue::List(rest) => {
+                        items.extend(rest.iter().cloned());
+                        Ok(LispValue::list(items))
+                    },
+                    LispValue::DottedList(rest, tail) => {
+                        items.extend(rest.iter().cloned());
+                        Ok(LispValue::dotted_list(items, *tail))
+                    },
+                    tail => Ok(LispValue::dotted_list(items, tail))
+                }
+            },
+            LispValue::Vector(ref items) => {
+                let items = items.borrow().clone();
+                self.quasiquote_items(&items, depth, fill).map(LispValue::vector)
+            },
+            _ => Ok(strip_syntax(template))
+        }
+    }
+
+    fn quasiquote_items(&self, items: &[LispValue], depth: usize,
+                        fill: &mut FnMut(&LispValue) -> LispResult) -> Result<Vec<LispValue>, LispError> {
+        let mut result = vec![];
+
+        for item in items {
+            match *item {
+                LispValue::List(ref splice) => match splice[..] {
+                    [ref keyword, ref expr] if self.is_keyword(keyword, "unquote-splicing") => {
+                        if depth == 1 {
+                            match try!(fill(expr)).resolve() {
+                                LispValue::List(values) => result.extend(values.iter().cloned()),
+                                value => return Err(format!("Cannot splice non-list: {}", value).into())
+                            }
+                        } else {
+                            let expr = try!(self.quasiquote(expr, depth - 1, fill));
+                            result.push(LispValue::abbreviation("unquote-splicing", expr));
+                        }
+                    },
+                    _ => result.push(try!(self.quasiquote(item, depth, fill)))
+                },
+                _ => result.push(try!(self.quasiquote(item, depth, fill)))
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+fn then<F>(step: Step, rest: F) -> Step where F: Fn(LispValue) -> Step + 'static {
+    Step::Then(Box::new(step), Rc::new(rest))
+}
+
+fn procedure<F>(params: &[&str], function: F) -> LispValue
+    where F: Fn(&[LispValue]) -> Step + 'static {
+    LispValue::PrimitiveFunction(PrimitiveFunction::control("", params, None, Rc::new(function)))
+}
+
+fn constant(value: LispValue) -> LispValue {
+    procedure(&[], move |_| Step::Done(Ok(value.clone())))
+}
+
+fn with_handler(handler: LispValue, thunk: LispValue, handlers: &Handlers) -> Step {
+    let outer = handlers.clone();
+    let inner = Some(Rc::new(Handler { procedure: handler, outer: outer.clone() }));
+    let thunk = then(Step::Apply(thunk, vec![]), move |value| {
+        Step::Handle(outer.clone(), Box::new(Step::Done(Ok(value))))
+    });
+
+    Step::Handle(inner, Box::new(thunk))
+}
+
+fn raise(handler: &Rc<Handler>, condition: LispValue, continuable: bool) -> Step {
+    let current = Some(handler.clone());
+    let call = then(Step::Apply(handler.procedure.clone(), vec![condition.clone()]), move |value| {
+        if continuable {
+            Step::Handle(current.clone(), Box::new(Step::Done(Ok(value))))
+        } else {
+            Step::Done(Err(format!("Handler returned from non-continuable exception: {}",
+                                   condition.write()).into()))
+        }
+    });
+
+    Step::Handle(handler.outer.clone(), Box::new(call))
+}
+
+fn wind(before: LispValue, thunk: LispValue, after: LispValue, winders: &Winders) -> Step {
+    let outer = winders.clone();
+    let inner = Some(Rc::new(Wind { before: before.clone(), after: after.clone(), parent: outer.clone() }));
+
+    then(Step::Apply(before, vec![]), move |_| {
+        let (outer, after) = (outer.clone(), after.clone());
+        let thunk = then(Step::Apply(thunk.clone(), vec![]), move |value| {
+            let after = then(Step::Apply(after.clone(), vec![]), move |_| Step::Done(Ok(value.clone())));
+            Step::Rewind(outer.clone(), Box::new(after))
+        });
+        Step::Rewind(inner.clone(), Box::new(thunk))
+    })
+}
+
+/// Goes from the extents `from` to `to`, then finishes with `result`.
+fn travel(from: &Winders, to: &Winders, result: LispResult) -> Step {
+    let mut path = vec![];
+    let mut left = from.clone();
+    while let Some(wind) = left {
+        if within(to, &wind) {
+            break;
+        }
+        path.push((wind.parent.clone(), wind.after.clone()));
+        left = wind.parent.clone();
+    }
+
+    let mut entered = vec![];
+    let mut right = to.clone();
+    while let Some(wind) = right {
+        if within(from, &wind) {
+            break;
+        }
+        entered.push((wind.parent.clone(), wind.before.clone()));
+        right = wind.parent.clone();
+    }
+    path.extend(entered.into_iter().rev());
+
+    wind_through(Rc::new(path), 0, to.clone(), result)
+}
+
+fn wind_through(path: Rc<Vec<(Winders, LispValue)>>, next: usize, to: Winders, result: LispResult) -> Step {
+    let (winders, thunk) = match path.get(next) {
+        Some(&(ref winders, ref thunk)) => (winders.clone(), thunk.clone()),
+        None => return Step::Rewind(to, Box::new(Step::Done(result)))
+    };
+    let rest = then(Step::Apply(thunk, vec![]), move |_| {
+        wind_through(path.clone(), next + 1, to.clone(), result.clone())
+    });
+
+    Step::Rewind(winders, Box::new(rest))
+}
+
+fn within(winders: &Winders, wind: &Rc<Wind>) -> bool {
+    let mut winders = winders;
+    while let Some(ref outer) = *winders {
+        if Rc::ptr_eq(outer, wind) {
+            return true;
+        }
+        winders = &outer.parent;
+    }
+    false
+}
+
+fn apply(procedure: &LispValue, args: &[LispValue]) -> Step {
+    let entered = match *procedure {
+        LispValue::PrimitiveFunction(ref f) => f.check_arity(args).map(|args| f.enter(&args)),
+        LispValue::Function(ref f) => f.check_arity(args).map(|args| f.enter(&args)),
+        LispValue::Continuation(_) => Err(format!("Too many args ({} for 1)", args.len()).into()),
+        _ => Err(format!("{} is not a function.", procedure).into())
+    };
+
+    entered.unwrap_or_else(|err| Step::Done(Err(err)))
+}
+
+/// Environments are compared by identity, as their frames may contain closures over them.
+impl PartialEq for LispEnvironment {
+    fn eq(&self, other: &LispEnvironment) -> bool {
+        Rc::ptr_eq(&self.frame, &other.frame)
+    }
+}
+
+impl Eq for LispEnvironment {}
+
+impl fmt::Debug for LispEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<environment>")
     }
 }
 
 impl Default for LispEnvironment {
     fn default() -> LispEnvironment {
-        let vtable = lisp_funcs!(
+        let mut vtable = lisp_funcs!(new;
             "+"    => [], xs, |args| numeric_op(args, &LispNum::zero(), &|a, e| a + e);
             "-"    => [], xs, |args| numeric_op(args, &LispNum::zero(), &|a, e| a - e);
             "*"    => [], xs, |args| numeric_op(args, &LispNum::one(), &|a, e| a * e);
-            ">"    => [], xs, |args| comparison_op(args, &|a, e| a > e);
-            "<"    => [], xs, |args| comparison_op(args, &|a, e| a < e);
-            ">="   => [], xs, |args| comparison_op(args, &|a, e| a >= e);
-            "<="   => [], xs, |args| comparison_op(args, &|a, e| a <= e);
-            "="    => [], xs, |args| comparison_op(args, &|a, e| a == e);
+            ">"    => [], xs, |args| comparison_op(args, &|o| o == Ordering::Greater);
+            "<"    => [], xs, |args| comparison_op(args, &|o| o == Ordering::Less);
+            ">="   => [], xs, |args| comparison_op(args, &|o| o != Ordering::Less);
+            "<="   => [], xs, |args| comparison_op(args, &|o| o != Ordering::Greater);
+            "="    => [], xs, |args| comparison_op(args, &|o| o == Ordering::Equal);
             "/"    => [x], xs, div;
+            "exact?"    => [z], nil, |args| assert_numericality(&args[0]).map(|n| LispValue::Boolean(n.is_exact()));
+            "inexact?"  => [z], nil, |args| assert_numericality(&args[0]).map(|n| LispValue::Boolean(!n.is_exact()));
+            "exact"     => [z], nil, |args| assert_numericality(&args[0]).and_then(|n| n.to_exact()
+                .map(LispValue::Number)
+                .ok_or_else(|| format!("No exact representation of {}", n).into()));
+            "inexact"   => [z], nil, |args| assert_numericality(&args[0]).map(|n| LispValue::Number(n.to_inexact()));
+            "nan?"      => [z], nil, |args| assert_numericality(&args[0]).map(|n| LispValue::Boolean(n.is_nan()));
+            "infinite?" => [z], nil, |args| assert_numericality(&args[0]).map(|n| LispValue::Boolean(n.is_infinite()));
+            "finite?"   => [z], nil, |args| assert_numericality(&args[0]).map(|n| LispValue::Boolean(n.is_finite()));
+            "number->string" => [z], radix, number_to_string;
+            "string->number" => [string], radix, string_to_number;
             "cons" => [car, cdr], nil, cons;
+            "write"   => [obj], nil, |args| { print!("{}", args[0].write()); Ok(LispValue::list(vec![])) };
+            "write-shared" => [obj], nil, |args| { print!("{}", args[0].write_shared()); Ok(LispValue::list(vec![])) };
+            "write-simple" => [obj], nil, |args| { print!("{}", args[0].write_simple()); Ok(LispValue::list(vec![])) };
+            "display" => [obj], nil, |args| { print!("{}", args[0].display()); Ok(LispValue::list(vec![])) };
+            "newline" => [], nil, |_| { println!(""); Ok(LispValue::list(vec![])) };
+            "char?"            => [x], nil, |args| Ok(LispValue::Boolean(assert_char(&args[0]).is_ok()));
+            "char->integer"    => [c], nil, |args| assert_char(&args[0])
+                .map(|c| LispValue::Number(LispNum::from_integer(BigInt::from(c as u32))));
+            "integer->char"    => [n], nil, integer_to_char;
+            "char-upcase"      => [c], nil, |args| char_map(args, &|c| single_char(c.to_uppercase(), c));
+            "char-downcase"    => [c], nil, |args| char_map(args, &|c| single_char(c.to_lowercase(), c));
+            "char-foldcase"    => [c], nil, |args| char_map(args, &fold_char);
+            "char-alphabetic?" => [c], nil, |args| char_test(args, &|c| c.is_alphabetic());
+            "char-numeric?"    => [c], nil, |args| char_test(args, &|c| c.is_numeric());
+            "char-whitespace?" => [c], nil, |args| char_test(args, &|c| c.is_whitespace());
+            "char-upper-case?" => [c], nil, |args| char_test(args, &|c| c.is_uppercase());
+            "char-lower-case?" => [c], nil, |args| char_test(args, &|c| c.is_lowercase());
+            "digit-value"      => [c], nil, |args| assert_char(&args[0]).map(|c| match c.to_digit(10) {
+                Some(d) => LispValue::Number(LispNum::from_integer(BigInt::from(d))),
+                None    => LispValue::Boolean(false)
+            });
+            "char=?"     => [], xs, |args| char_comparison_op(args, &|a, e| a == e);
+            "char<?"     => [], xs, |args| char_comparison_op(args, &|a, e| a < e);
+            "char>?"     => [], xs, |args| char_comparison_op(args, &|a, e| a > e);
+            "char<=?"    => [], xs, |args| char_comparison_op(args, &|a, e| a <= e);
+            "char>=?"    => [], xs, |args| char_comparison_op(args, &|a, e| a >= e);
+            "char-ci=?"  => [], xs, |args| char_comparison_op(args, &|a, e| fold_char(a) == fold_char(e));
+            "char-ci<?"  => [], xs, |args| char_comparison_op(args, &|a, e| fold_char(a) < fold_char(e));
+            "char-ci>?"  => [], xs, |args| char_comparison_op(args, &|a, e| fold_char(a) > fold_char(e));
+            "char-ci<=?" => [], xs, |args| char_comparison_op(args, &|a, e| fold_char(a) <= fold_char(e));
+            "char-ci>=?" => [], xs, |args| char_comparison_op(args, &|a, e| fold_char(a) >= fold_char(e));
+            "vector?"         => [x], nil, |args| Ok(LispValue::Boolean(assert_vector(&args[0]).is_ok()));
+            "vector"          => [], xs, |args| Ok(LispValue::vector(args.to_vec()));
+            "make-vector"     => [k], fill, make_vector;
+            "vector-length"   => [v], nil, |args| assert_vector(&args[0])
+                .map(|v| LispValue::Number(LispNum::from_integer(BigInt::from(v.borrow().len()))));
+            "vector-ref"      => [v, k], nil, vector_ref;
+            "vector-set!"     => [v, k, obj], nil, vector_set;
+            "vector->list"    => [v], range, |args| vector_slice(args).map(LispValue::list);
+            "list->vector"    => [list], nil, |args| assert_list(&args[0]).map(LispValue::vector);
+            "vector-fill!"    => [v, fill], range, vector_fill;
+            "vector-copy"     => [v], range, |args| vector_slice(args).map(LispValue::vector);
+            "vector-copy!"    => [to, at, from], range, vector_copy_into;
+            "vector-append"   => [], vs, vector_append;
+            "bytevector?"         => [x], nil, |args| Ok(LispValue::Boolean(assert_bytevector(&args[0]).is_ok()));
+            "bytevector"          => [], bytes, |args| args.iter().map(assert_byte).collect::<Result<_, _>>()
+                .map(LispValue::bytevector);
+            "make-bytevector"     => [k], fill, make_bytevector;
+            "bytevector-length"   => [bv], nil, |args| assert_bytevector(&args[0])
+                .map(|bv| LispValue::Number(LispNum::from_integer(BigInt::from(bv.borrow().len()))));
+            "bytevector-u8-ref"   => [bv, k], nil, bytevector_ref;
+            "bytevector-u8-set!"  => [bv, k, byte], nil, bytevector_set;
+            "bytevector-copy"     => [bv], range, |args| bytevector_slice(args).map(LispValue::bytevector);
+            "bytevector-copy!"    => [to, at, from], range, bytevector_copy_into;
+            "bytevector-append"   => [], bvs, bytevector_append;
+            "utf8->string"        => [bv], range, utf8_to_string;
+            "string->utf8"        => [string], range, string_to_utf8;
         );
-        LispEnvironment {vtable: vtable}
+        vtable.extend(lisp_funcs!(control;
+            "call-with-current-continuation" => [proc], nil, |args| Step::CallCC(args[0].clone());
+            "call/cc"         => [proc], nil, |args| Step::CallCC(args[0].clone());
+            "dynamic-wind"    => [before, thunk, after], nil,
+                |args| Step::Wind(args[0].clone(), args[1].clone(), args[2].clone());
+            "with-exception-handler" => [handler, thunk], nil,
+                |args| Step::WithHandler(args[0].clone(), args[1].clone());
+            "raise"             => [obj], nil, |args| Step::Raise(args[0].clone(), false);
+            "raise-continuable" => [obj], nil, |args| Step::Raise(args[0].clone(), true);
+            "error"             => [message], irritants, |args| match assert_string(&args[0]) {
+                Ok(message) => {
+                    let error = LispError { irritants: args[1..].to_vec(), ..LispError::new(&message) };
+                    Step::Raise(LispValue::Error(Rc::new(error)), false)
+                },
+                Err(err) => Step::Done(Err(err))
+            };
+            "error-object?"          => [obj], nil, |args| Step::Done(Ok(LispValue::Boolean(
+                assert_error(&args[0]).is_ok())));
+            "error-object-message"   => [e], nil, |args| Step::Done(assert_error(&args[0])
+                .map(|e| LispValue::String(e.message.clone())));
+            "error-object-irritants" => [e], nil, |args| Step::Done(assert_error(&args[0])
+                .map(|e| LispValue::list(e.irritants.clone())));
+            "read-error?"            => [obj], nil, |args| Step::Done(Ok(LispValue::Boolean(
+                assert_error(&args[0]).map_or(false, |e| e.kind == ErrorKind::Read))));
+            "file-error?"            => [obj], nil, |args| Step::Done(Ok(LispValue::Boolean(
+                assert_error(&args[0]).map_or(false, |e| e.kind == ErrorKind::File))));
+            "vector-map"      => [f, v], vs, |args| vector_map(args, true);
+            "vector-for-each" => [f, v], vs, |args| vector_map(args, false);
+        ));
+
+        let world = LispEnvironment::new(vtable, None);
+        let (once, fully) = (world.downgrade(), world.downgrade());
+        world.frame.borrow_mut().vtable.extend(lisp_funcs!(new;
+            "macroexpand-1" => [form], nil, move |args| macroexpand_in(&once, &args[0], LispEnvironment::macroexpand_1);
+            "macroexpand"   => [form], nil, move |args| macroexpand_in(&fully, &args[0], LispEnvironment::macroexpand);
+        ));
+        world
+    }
+}
+
+fn macroexpand_in(world: &WeakEnvironment, form: &LispValue,
+                  expand: fn(&LispEnvironment, &LispValue) -> LispResult) -> LispResult {
+    match world.upgrade() {
+        Some(world) => expand(&world, form).map(|expansion| world.unrename(&expansion)),
+        None => Err("The environment of macroexpand no longer exists".into())
     }
 }
 
@@ -262,22 +1413,20 @@ fn numeric_op(operands: &[LispValue],
 
 fn div(operands: &[LispValue]) -> LispResult {
     let numbers: Vec<LispNum> = try!(operands.iter().map(assert_numericality).collect());
-    let zero: LispNum = LispNum::zero();
 
     match numbers[..] {
         []  => Err("Not enough arguments.".into()),
-        [ref n] => if n.is_zero() {
+        [ref n] => if is_exact_zero(n) {
                 Err("Cannot divide by zero.".into())
             } else {
-                Ok(LispValue::Number(LispNum::one() / n))
+                Ok(LispValue::Number(LispNum::one() / n.clone()))
             },
         [ref n, ref rest..] => {
-            if n.is_zero() { return Ok(LispValue::Number(zero)) }
             let numbers = rest.iter().map(|item|
-                if item.is_zero() {
+                if is_exact_zero(item) {
                     Err("Cannot divide by zero.".into())
                 } else {
-                    Ok(item)
+                    Ok(item.clone())
                 }
             );
             result_fold(numbers, n.clone(), |a, e| a / e).map(LispValue::Number)
@@ -285,36 +1434,447 @@ fn div(operands: &[LispValue]) -> LispResult {
     }
 }
 
+fn is_exact_zero(n: &LispNum) -> bool {
+    n.is_exact() && n.is_zero()
+}
+
 fn comparison_op(operands: &[LispValue],
-               fold: &Fn(&LispNum, &LispNum) -> bool) -> LispResult {
+               fold: &Fn(Ordering) -> bool) -> LispResult {
     let numbers: Vec<LispNum> = try!(operands.iter().map(assert_numericality).collect());
     if numbers.len() < 2 { return Err("Need at least two args to compare".into()); }
-    let val = numbers.iter().zip(&numbers[1..]).all(|(a, b)| fold(a, b));
+    let val = numbers.iter().zip(&numbers[1..]).all(|(a, b)| a.compare(b).map_or(false, fold));
 
     Ok(LispValue::Boolean(val))
 }
 
-fn assert_numericality(item: &LispValue) -> Result<LispNum, String> {
+fn assert_radix(operands: &[LispValue]) -> Result<u32, LispError> {
+    match operands.len() {
+        0 => Ok(10),
+        1 => {
+            let n = try!(assert_numericality(&operands[0]));
+            match n.to_exact_integer().and_then(|n| n.to_u32()) {
+                Some(radix @ 2) | Some(radix @ 8) | Some(radix @ 10) | Some(radix @ 16) => Ok(radix),
+                _ => Err(format!("Invalid radix: {}", n).into())
+            }
+        },
+        n => Err(format!("Too many args ({} for 2)", n + 1).into())
+    }
+}
+
+fn number_to_string(operands: &[LispValue]) -> LispResult {
+    let n = try!(assert_numericality(&operands[0]));
+    let radix = try!(assert_radix(&operands[1..]));
+
+    n.to_str_radix(radix)
+        .map(LispValue::String)
+        .map_err(LispError::from)
+}
+
+fn string_to_number(operands: &[LispValue]) -> LispResult {
+    let string = try!(assert_string(&operands[0]));
+    let radix = try!(assert_radix(&operands[1..]));
+
+    Ok(LispNum::from_str_radix(&string, radix)
+        .map(LispValue::Number)
+        .unwrap_or(LispValue::Boolean(false)))
+}
+
+fn assert_numericality(item: &LispValue) -> Result<LispNum, LispError> {
     match *item {
         LispValue::Number(ref n) => Ok(n.clone()),
-        _ => Err(format!("Non-numeric operand: {}", item)),
+        _ => Err(format!("Non-numeric operand: {}", item).into()),
+    }
+}
+
+fn assert_char(item: &LispValue) -> Result<char, LispError> {
+    match *item {
+        LispValue::Char(c) => Ok(c),
+        _ => Err(format!("Non-character operand: {}", item).into()),
+    }
+}
+
+fn fold_char(c: char) -> char {
+    single_char(c.to_lowercase(), c)
+}
+
+fn single_char<I: Iterator<Item = char>>(mut mapped: I, c: char) -> char {
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => m,
+        _ => c
+    }
+}
+
+fn char_map(operands: &[LispValue], map: &Fn(char) -> char) -> LispResult {
+    assert_char(&operands[0]).map(|c| LispValue::Char(map(c)))
+}
+
+fn char_test(operands: &[LispValue], test: &Fn(char) -> bool) -> LispResult {
+    assert_char(&operands[0]).map(|c| LispValue::Boolean(test(c)))
+}
+
+fn char_comparison_op(operands: &[LispValue],
+                      fold: &Fn(char, char) -> bool) -> LispResult {
+    let chars: Vec<char> = try!(operands.iter().map(assert_char).collect());
+    if chars.len() < 2 { return Err("Need at least two args to compare".into()); }
+    let val = chars.iter().zip(&chars[1..]).all(|(&a, &b)| fold(a, b));
+
+    Ok(LispValue::Boolean(val))
+}
+
+fn integer_to_char(operands: &[LispValue]) -> LispResult {
+    let n = try!(assert_numericality(&operands[0]));
+    let code_point = n.to_exact_integer().and_then(|n| n.to_u32());
+
+    code_point.and_then(::std::char::from_u32)
+        .map(LispValue::Char)
+        .ok_or_else(|| format!("Invalid code point: {}", n).into())
+}
+
+fn assert_vector(item: &LispValue) -> Result<Rc<RefCell<Vec<LispValue>>>, LispError> {
+    match item.resolve() {
+        LispValue::Vector(v) => Ok(v),
+        _ => Err(format!("Non-vector operand: {}", item).into()),
     }
 }
 
+fn assert_list(item: &LispValue) -> Result<Vec<LispValue>, LispError> {
+    match item.resolve() {
+        LispValue::List(xs) => Ok(xs.to_vec()),
+        _ => Err(format!("Not a proper list: {}", item).into()),
+    }
+}
+
+fn assert_index(item: &LispValue) -> Result<usize, LispError> {
+    let n = try!(assert_numericality(item));
+    let index = n.to_exact_integer().and_then(|n| n.to_usize());
+
+    index.ok_or_else(|| format!("Invalid index: {}", n).into())
+}
+
+fn assert_in_bounds(index: usize, len: usize) -> Result<usize, LispError> {
+    if index < len {
+        Ok(index)
+    } else {
+        Err(format!("Index {} out of bounds (length {})", index, len).into())
+    }
+}
+
+fn assert_range(operands: &[LispValue], len: usize) -> Result<(usize, usize), LispError> {
+    let start = try!(operands.get(0).map_or(Ok(0), assert_index));
+    let end = try!(operands.get(1).map_or(Ok(len), assert_index));
+
+    match operands.len() {
+        0...2 if start <= end && end <= len => Ok((start, end)),
+        0...2 => Err(format!("Invalid range {}..{} (length {})", start, end, len).into()),
+        _ => Err("Too many args for range".into())
+    }
+}
+
+fn make_vector(operands: &[LispValue]) -> LispResult {
+    let len = try!(assert_index(&operands[0]));
+    let fill = match operands.len() {
+        1 => LispValue::Boolean(false),
+        2 => operands[1].clone(),
+        n => return Err(format!("Too many args ({} for 2)", n).into())
+    };
+
+    Ok(LispValue::vector(vec![fill; len]))
+}
+
+fn vector_ref(operands: &[LispValue]) -> LispResult {
+    let vector = try!(assert_vector(&operands[0]));
+    let vector = vector.borrow();
+    let index = try!(assert_index(&operands[1]).and_then(|k| assert_in_bounds(k, vector.len())));
+
+    Ok(vector[index].clone())
+}
+
+fn vector_set(operands: &[LispValue]) -> LispResult {
+    let vector = try!(assert_vector(&operands[0]));
+    let mut vector = vector.borrow_mut();
+    let index = try!(assert_index(&operands[1]).and_then(|k| assert_in_bounds(k, vector.len())));
+
+    vector[index] = operands[2].clone();
+    Ok(LispValue::list(vec![]))
+}
+
+fn vector_slice(operands: &[LispValue]) -> Result<Vec<LispValue>, LispError> {
+    let vector = try!(assert_vector(&operands[0]));
+    let vector = vector.borrow();
+    let (start, end) = try!(assert_range(&operands[1..], vector.len()));
+
+    Ok(vector[start..end].to_vec())
+}
+
+fn vector_fill(operands: &[LispValue]) -> LispResult {
+    let vector = try!(assert_vector(&operands[0]));
+    let mut vector = vector.borrow_mut();
+    let (start, end) = try!(assert_range(&operands[2..], vector.len()));
+
+    for item in &mut vector[start..end] {
+        *item = operands[1].clone();
+    }
+    Ok(LispValue::list(vec![]))
+}
+
+fn vector_copy_into(operands: &[LispValue]) -> LispResult {
+    let to = try!(assert_vector(&operands[0]));
+    let at = try!(assert_index(&operands[1]));
+    let items = try!(vector_slice(&operands[2..]));
+    let mut to = to.borrow_mut();
+
+    if at > to.len() || to.len() - at < items.len() {
+        return Err(format!("Cannot copy {} items to index {} (length {})",
+                           items.len(), at, to.len()).into());
+    }
+    for (i, item) in items.into_iter().enumerate() {
+        to[at + i] = item;
+    }
+    Ok(LispValue::list(vec![]))
+}
+
+fn vector_append(operands: &[LispValue]) -> LispResult {
+    let vectors: Vec<_> = try!(operands.iter().map(assert_vector).collect());
+    let items = vectors.iter()
+        .flat_map(|v| v.borrow().clone())
+        .collect();
+
+    Ok(LispValue::vector(items))
+}
+
+fn vector_map(operands: &[LispValue], collect: bool) -> Step {
+    let vectors: Vec<Vec<LispValue>> = match operands[1..].iter()
+        .map(|v| assert_vector(v).map(|v| v.borrow().clone()))
+        .collect() {
+        Ok(vectors) => vectors,
+        Err(err) => return Step::Done(Err(err))
+    };
+    let len = vectors.iter().map(|v| v.len()).min().unwrap_or(0);
+    let rows = (0..len)
+        .map(|i| vectors.iter().map(|v| v[i].clone()).collect())
+        .collect();
+
+    map_rows(operands[0].clone(), Rc::new(rows), 0, vec![], collect)
+}
+
+fn map_rows(procedure: LispValue, rows: Rc<Vec<Vec<LispValue>>>, next: usize,
+            results: Vec<LispValue>, collect: bool) -> Step {
+    let row = match rows.get(next) {
+        Some(row) => row.clone(),
+        None => return Step::Done(Ok(if collect { LispValue::vector(results) } else { LispValue::list(vec![]) }))
+    };
+
+    then(Step::Apply(procedure.clone(), row), move |value| {
+        let mut results = results.clone();
+        if collect {
+            results.push(value);
+        }
+        map_rows(procedure.clone(), rows.clone(), next + 1, results, collect)
+    })
+}
+
+fn assert_bytevector(item: &LispValue) -> Result<Rc<RefCell<Vec<u8>>>, LispError> {
+    match *item {
+        LispValue::Bytevector(ref bv) => Ok(bv.clone()),
+        _ => Err(format!("Non-bytevector operand: {}", item).into()),
+    }
+}
+
+fn assert_byte(item: &LispValue) -> Result<u8, LispError> {
+    let n = try!(assert_numericality(item));
+    let byte = n.to_exact_integer().and_then(|n| n.to_u8());
+
+    byte.ok_or_else(|| format!("Invalid byte: {}", n).into())
+}
+
+fn assert_error(item: &LispValue) -> Result<Rc<LispError>, LispError> {
+    match *item {
+        LispValue::Error(ref e) => Ok(e.clone()),
+        _ => Err(format!("Non-error-object operand: {}", item).into()),
+    }
+}
+
+fn assert_string(item: &LispValue) -> Result<String, LispError> {
+    match *item {
+        LispValue::String(ref s) => Ok(s.clone()),
+        _ => Err(format!("Non-string operand: {}", item).into()),
+    }
+}
+
+fn is_true(value: &LispValue) -> bool {
+    *value != LispValue::Boolean(false)
+}
+
+fn is_eqv(a: &LispValue, b: &LispValue) -> bool {
+    match (a, b) {
+        (&LispValue::List(ref a), &LispValue::List(ref b)) => Rc::ptr_eq(a, b) || (a.is_empty() && b.is_empty()),
+        (&LispValue::DottedList(ref a, _), &LispValue::DottedList(ref b, _)) => Rc::ptr_eq(a, b),
+        (&LispValue::Vector(ref a), &LispValue::Vector(ref b)) => Rc::ptr_eq(a, b),
+        (&LispValue::Bytevector(ref a), &LispValue::Bytevector(ref b)) => Rc::ptr_eq(a, b),
+        (&LispValue::Error(ref a), &LispValue::Error(ref b)) => Rc::ptr_eq(a, b),
+        (&LispValue::Atom(_), _) | (&LispValue::Number(_), _) | (&LispValue::Char(_), _) |
+        (&LispValue::Boolean(_), _) | (&LispValue::Shared(_), _) |
+        (&LispValue::PrimitiveFunction(_), _) | (&LispValue::Function(_), _) |
+        (&LispValue::Continuation(_), _) => a == b,
+        _ => false
+    }
+}
+
+fn uninitialised(name: &str) -> LispError {
+    format!("Variable used before its initialisation: '{}'", name).into()
+}
+
+fn parameter_names(params: &LispValue) -> Vec<String> {
+    match *params {
+        LispValue::Atom(ref name) => vec![name.clone()],
+        LispValue::List(ref params) => params.iter().flat_map(parameter_names).collect(),
+        LispValue::DottedList(ref params, ref rest) =>
+            params.iter().chain(iter::once(&**rest)).flat_map(parameter_names).collect(),
+        _ => vec![]
+    }
+}
+
+fn binding_names(bindings: Option<&LispValue>) -> Vec<String> {
+    match bindings {
+        Some(&LispValue::List(ref bindings)) => bindings.iter().filter_map(|binding| match *binding {
+            LispValue::List(ref parts) => match parts.first() {
+                Some(&LispValue::Atom(ref name)) => Some(name.clone()),
+                _ => None
+            },
+            _ => None
+        }).collect(),
+        _ => vec![]
+    }
+}
+
+fn unrename(form: &LispValue, bound: &HashSet<String>) -> LispValue {
+    match *form {
+        LispValue::Atom(ref name) if bound.contains(name) => match unalias(name) {
+            Some((_, expansion)) => LispValue::Atom(format!("{}.{}", base_name(name), expansion)),
+            None => form.clone()
+        },
+        LispValue::List(ref items) => LispValue::list(items.iter().map(|item| unrename(item, bound)).collect()),
+        LispValue::DottedList(ref items, ref tail) => LispValue::dotted_list(
+            items.iter().map(|item| unrename(item, bound)).collect(),
+            unrename(tail, bound)),
+        _ => strip_syntax(form)
+    }
+}
+
+fn let_bindings(bindings: &LispValue) -> Result<Vec<(String, LispValue)>, LispError> {
+    let bindings = match *bindings {
+        LispValue::List(ref bindings) => bindings,
+        _ => return Err(format!("Invalid bindings: {}", bindings).into())
+    };
+
+    bindings.iter().map(|binding| match *binding {
+        LispValue::List(ref parts) => match parts[..] {
+            [LispValue::Atom(ref name), ref init] => Ok((name.clone(), init.clone())),
+            _ => Err(format!("Invalid binding: {}", binding).into())
+        },
+        _ => Err(format!("Invalid binding: {}", binding).into())
+    }).collect()
+}
+
+fn read_file(path: &LispValue, fold_case: bool) -> Result<Vec<LispValue>, LispError> {
+    let path = try!(assert_string(path));
+    let mut source = String::new();
+    try!(File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| LispError::of_kind(&format!("Cannot include {}: {}", path, err), ErrorKind::File)));
+
+    let forms = if fold_case { program_ci(&source) } else { program(&source) };
+    forms.map_err(|err| LispError::of_kind(&format!("{}:{}", path, err), ErrorKind::Read))
+}
+
+fn make_bytevector(operands: &[LispValue]) -> LispResult {
+    let len = try!(assert_index(&operands[0]));
+    let fill = match operands.len() {
+        1 => 0,
+        2 => try!(assert_byte(&operands[1])),
+        n => return Err(format!("Too many args ({} for 2)", n).into())
+    };
+
+    Ok(LispValue::bytevector(vec![fill; len]))
+}
+
+fn bytevector_ref(operands: &[LispValue]) -> LispResult {
+    let bytevector = try!(assert_bytevector(&operands[0]));
+    let bytevector = bytevector.borrow();
+    let index = try!(assert_index(&operands[1]).and_then(|k| assert_in_bounds(k, bytevector.len())));
+
+    Ok(LispValue::Number(LispNum::from_integer(BigInt::from(bytevector[index]))))
+}
+
+fn bytevector_set(operands: &[LispValue]) -> LispResult {
+    let bytevector = try!(assert_bytevector(&operands[0]));
+    let mut bytevector = bytevector.borrow_mut();
+    let index = try!(assert_index(&operands[1]).and_then(|k| assert_in_bounds(k, bytevector.len())));
+
+    bytevector[index] = try!(assert_byte(&operands[2]));
+    Ok(LispValue::list(vec![]))
+}
+
+fn bytevector_slice(operands: &[LispValue]) -> Result<Vec<u8>, LispError> {
+    let bytevector = try!(assert_bytevector(&operands[0]));
+    let bytevector = bytevector.borrow();
+    let (start, end) = try!(assert_range(&operands[1..], bytevector.len()));
+
+    Ok(bytevector[start..end].to_vec())
+}
+
+fn bytevector_copy_into(operands: &[LispValue]) -> LispResult {
+    let to = try!(assert_bytevector(&operands[0]));
+    let at = try!(assert_index(&operands[1]));
+    let bytes = try!(bytevector_slice(&operands[2..]));
+    let mut to = to.borrow_mut();
+
+    if at > to.len() || to.len() - at < bytes.len() {
+        return Err(format!("Cannot copy {} bytes to index {} (length {})",
+                           bytes.len(), at, to.len()).into());
+    }
+    to[at..at + bytes.len()].copy_from_slice(&bytes);
+    Ok(LispValue::list(vec![]))
+}
+
+fn bytevector_append(operands: &[LispValue]) -> LispResult {
+    let bytevectors: Vec<_> = try!(operands.iter().map(assert_bytevector).collect());
+    let bytes = bytevectors.iter()
+        .flat_map(|bv| bv.borrow().clone())
+        .collect();
+
+    Ok(LispValue::bytevector(bytes))
+}
+
+fn utf8_to_string(operands: &[LispValue]) -> LispResult {
+    let bytes = try!(bytevector_slice(operands));
+
+    String::from_utf8(bytes)
+        .map(LispValue::String)
+        .map_err(|err| format!("Invalid UTF-8: {}", err).into())
+}
+
+fn string_to_utf8(operands: &[LispValue]) -> LispResult {
+    let chars: Vec<char> = try!(assert_string(&operands[0])).chars().collect();
+    let (start, end) = try!(assert_range(&operands[1..], chars.len()));
+    let string: String = chars[start..end].iter().cloned().collect();
+
+    Ok(LispValue::bytevector(string.into_bytes()))
+}
+
 fn cons(operands: &[LispValue]) -> LispResult {
-    match *operands {
-        [ref elt, LispValue::List(ref xs)] => {
-            let mut new_list = xs.clone();
+    match (&operands[0], operands[1].resolve()) {
+        (elt, LispValue::List(xs)) => {
+            let mut new_list = xs.to_vec();
             new_list.insert(0, elt.clone());
-            Ok(LispValue::List(new_list))
+            Ok(LispValue::list(new_list))
         },
-        [ref elt, LispValue::DottedList(ref xs, ref xlast)] => {
-            let mut new_list = xs.clone();
+        (elt, LispValue::DottedList(xs, xlast)) => {
+            let mut new_list = xs.to_vec();
             new_list.insert(0, elt.clone());
-            Ok(LispValue::DottedList(new_list, xlast.clone()))
+            Ok(LispValue::dotted_list(new_list, *xlast))
         },
-        [ref elt1, ref elt2] => Ok(LispValue::DottedList(vec![elt1.clone()], Box::new(elt2.clone()))),
-        _ => unreachable!()
+        (elt1, _) => Ok(LispValue::dotted_list(vec![elt1.clone()], operands[1].clone()))
     }
 }
 
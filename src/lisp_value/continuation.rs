@@ -0,0 +1,43 @@
+use std::fmt;
+use std::rc::Rc;
+
+use lisp_environment::{Stack, Winders, Handlers};
+
+#[derive(Clone)]
+pub struct Continuation {
+    stack: Rc<Stack>,
+    winders: Winders,
+    handlers: Handlers
+}
+
+impl Continuation {
+    pub fn new(stack: Stack, winders: Winders, handlers: Handlers) -> Continuation {
+        Continuation { stack: Rc::new(stack), winders: winders, handlers: handlers }
+    }
+
+    pub fn stack(&self) -> Stack {
+        (*self.stack).clone()
+    }
+
+    pub fn winders(&self) -> &Winders {
+        &self.winders
+    }
+
+    pub fn handlers(&self) -> &Handlers {
+        &self.handlers
+    }
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Continuation) -> bool {
+        Rc::ptr_eq(&self.stack, &other.stack)
+    }
+}
+
+impl Eq for Continuation {}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<continuation>")
+    }
+}
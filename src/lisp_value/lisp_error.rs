@@ -0,0 +1,79 @@
+use std::fmt;
+use std::rc::Rc;
+
+use lisp_value::LispValue;
+use reader::Span;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Error,
+    Read,
+    File,
+    Interrupt
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LispError {
+    pub message: String,
+    pub irritants: Vec<LispValue>,
+    pub kind: ErrorKind,
+    pub raised: Option<LispValue>,
+    pub span: Option<Span>
+}
+
+impl LispError {
+    pub fn new(message: &str) -> LispError {
+        LispError::of_kind(message, ErrorKind::Error)
+    }
+
+    pub fn of_kind(message: &str, kind: ErrorKind) -> LispError {
+        LispError { message: message.into(), irritants: vec![], kind: kind, raised: None, span: None }
+    }
+
+    pub fn uncaught(condition: LispValue) -> LispError {
+        let error = match condition {
+            LispValue::Error(ref error) => (**error).clone(),
+            _ => LispError::new(&format!("Uncaught exception: {}", condition.write()))
+        };
+
+        LispError { raised: Some(condition), ..error }
+    }
+
+    /// What a handler is given for this error: the object raised, or else the error itself.
+    pub fn condition(&self) -> LispValue {
+        match self.raised {
+            Some(ref condition) => condition.clone(),
+            None => LispValue::Error(Rc::new(self.clone()))
+        }
+    }
+
+    /// Records where the error happened, unless a nested form already has.
+    pub fn at(self, span: Span) -> LispError {
+        match self.span {
+            Some(_) => self,
+            None    => LispError { span: Some(span), ..self }
+        }
+    }
+}
+
+impl From<String> for LispError {
+    fn from(message: String) -> LispError {
+        LispError::new(&message)
+    }
+}
+
+impl<'a> From<&'a str> for LispError {
+    fn from(message: &'a str) -> LispError {
+        LispError::new(message)
+    }
+}
+
+impl fmt::Display for LispError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.message));
+        for irritant in &self.irritants {
+            try!(write!(f, " {}", irritant.write()));
+        }
+        Ok(())
+    }
+}
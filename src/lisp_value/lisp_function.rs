@@ -1,65 +1,63 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
-use lisp_value::{LispValue, LispResult};
-use lisp_environment::LispEnvironment;
+use lisp_value::{LispValue, LispError};
+use lisp_value::syntax_rules::base_name;
+use lisp_environment::{LispEnvironment, Step};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct LispFunction {
     args: Vec<String>,
     varargs: Option<String>,
     body: Vec<LispValue>,
-    closure: Rc<RefCell<LispEnvironment>>
+    closure: LispEnvironment
 }
 
 impl LispFunction {
     pub fn new(env: &LispEnvironment, args: &[LispValue], body: &[LispValue]) -> LispFunction {
         LispFunction {
-            args: args.iter().map(|x| x.to_string()).collect(),
+            args: args.iter().map(parameter_name).collect(),
             varargs: None,
             body: body.to_vec(),
-            closure: Rc::new(RefCell::new(env.clone()))
+            closure: env.clone()
         }
     }
 
     pub fn new_with_varargs(env: &LispEnvironment, args: &[LispValue],
                             varargs: LispValue, body: &[LispValue]) -> LispFunction {
         LispFunction {
-            args: args.iter().map(|x| x.to_string()).collect(),
-            varargs: Some(varargs.to_string()),
+            args: args.iter().map(parameter_name).collect(),
+            varargs: Some(parameter_name(&varargs)),
             body: body.to_vec(),
-            closure: Rc::new(RefCell::new(env.clone()))
+            closure: env.clone()
         }
     }
 
     pub fn arg_list(&self) -> String {
-        let mut args = self.args.clone();
+        let mut args: Vec<_> = self.args.iter().map(|arg| base_name(arg).to_string()).collect();
 
         if let Some(ref varargs) = self.varargs {
-            args.push(format!("{}...", varargs))
+            args.push(format!("{}...", base_name(varargs)))
         };
 
         args.join(", ")
     }
 
-    pub fn check_arity(&self, args: &[LispValue]) -> Result<Vec<LispValue>, String> {
+    pub fn check_arity(&self, args: &[LispValue]) -> Result<Vec<LispValue>, LispError> {
         let (required, given) = (self.args.len(), args.len());
 
         if given < required {
             let at_least = if self.varargs.is_some() { "at least " } else { "" };
-            Err(format!("Not enough args ({} for {}{})", given, at_least, required))
+            Err(format!("Not enough args ({} for {}{})", given, at_least, required).into())
         } else if given > required && self.varargs.is_none() {
-            Err(format!("Too many args ({} for {})", given, required))
+            Err(format!("Too many args ({} for {})", given, required).into())
         } else {
             Ok(args.to_vec())
         }
     }
 
-    pub fn call(&self, src_env: &LispEnvironment, params: &[LispValue]) -> LispResult {
-        let mut env = src_env.merge(&self.closure.borrow());
+    pub fn enter(&self, params: &[LispValue]) -> Step {
+        let env = self.closure.extend();
 
         for (name, value) in self.args.iter().zip(params) {
-            env.set(name, value.clone());
+            env.define(name, value.clone());
         }
 
         if let Some(ref name) = self.varargs {
@@ -67,13 +65,17 @@ impl LispFunction {
                 .skip(self.args.len())
                 .cloned()
                 .collect::<Vec<_>>();
-            env.set(name, LispValue::List(values))
+            env.define(name, LispValue::list(values))
         }
 
-        let (result, world) = env.eval_many(&self.body);
-        let mut new_env = self.closure.borrow_mut();
-        *new_env = world;
-        result
+        env.eval_body(&self.body)
     }
+}
 
+/// Takes parameter names as they are, rather than as they print.
+fn parameter_name(param: &LispValue) -> String {
+    match *param {
+        LispValue::Atom(ref name) => name.clone(),
+        _ => param.to_string()
+    }
 }
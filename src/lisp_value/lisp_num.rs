@@ -0,0 +1,289 @@
+use std::cmp::{self, Ordering};
+use std::fmt;
+use std::f64;
+use std::ops::{Add, Sub, Mul, Div};
+use std::str::FromStr;
+
+use num::{Zero, One, Num, ToPrimitive, pow};
+use num::bigint::BigInt;
+use num::rational::BigRational;
+
+/// The largest power of ten an exact number may be written with.
+const MAX_EXACT_EXPONENT: i64 = 4096;
+
+#[derive(Clone, Debug)]
+pub enum LispNum {
+    Exact(BigRational),
+    Inexact(f64)
+}
+
+impl LispNum {
+    pub fn from_integer(n: BigInt) -> LispNum {
+        LispNum::Exact(BigRational::from_integer(n))
+    }
+
+    pub fn is_exact(&self) -> bool {
+        match *self {
+            LispNum::Exact(_) => true,
+            LispNum::Inexact(_) => false
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match *self {
+            LispNum::Exact(ref n) => n.is_integer(),
+            LispNum::Inexact(x) => x.is_finite() && x.fract() == 0.0
+        }
+    }
+
+    pub fn is_nan(&self) -> bool {
+        match *self {
+            LispNum::Exact(_) => false,
+            LispNum::Inexact(x) => x.is_nan()
+        }
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        match *self {
+            LispNum::Exact(_) => false,
+            LispNum::Inexact(x) => x.is_infinite()
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        match *self {
+            LispNum::Exact(_) => true,
+            LispNum::Inexact(x) => x.is_finite()
+        }
+    }
+
+    pub fn to_exact_integer(&self) -> Option<BigInt> {
+        match *self {
+            LispNum::Exact(ref n) if n.is_integer() => Some(n.to_integer()),
+            _ => None
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match *self {
+            LispNum::Exact(ref n) => {
+                // Scale both parts down together when either is too large for an f64.
+                let bits = cmp::max(n.numer().bits(), n.denom().bits());
+                let shift = bits.saturating_sub(f64::MAX_EXP as usize - 2);
+                let numer = (n.numer() >> shift).to_f64().unwrap_or(f64::NAN);
+                let denom = (n.denom() >> shift).to_f64().unwrap_or(f64::NAN);
+                numer / denom
+            },
+            LispNum::Inexact(x) => x
+        }
+    }
+
+    pub fn to_exact(&self) -> Option<LispNum> {
+        match *self {
+            LispNum::Exact(_) => Some(self.clone()),
+            LispNum::Inexact(x) => BigRational::from_float(x).map(LispNum::Exact)
+        }
+    }
+
+    pub fn to_inexact(&self) -> LispNum {
+        LispNum::Inexact(self.to_f64())
+    }
+
+    pub fn from_str_radix(s: &str, radix: u32) -> Result<LispNum, String> {
+        let invalid = || format!("Invalid number: {}", s);
+        let (mut radix, mut exactness, mut body) = (radix, None, s);
+        let (mut radix_seen, mut exactness_seen) = (false, false);
+
+        while body.starts_with('#') && body.len() >= 2 {
+            match &body[1..2] {
+                "x" | "X" if !radix_seen => { radix = 16; radix_seen = true },
+                "b" | "B" if !radix_seen => { radix = 2; radix_seen = true },
+                "o" | "O" if !radix_seen => { radix = 8; radix_seen = true },
+                "d" | "D" if !radix_seen => { radix = 10; radix_seen = true },
+                "e" | "E" if !exactness_seen => { exactness = Some(true); exactness_seen = true },
+                "i" | "I" if !exactness_seen => { exactness = Some(false); exactness_seen = true },
+                _ => return Err(invalid())
+            }
+            body = &body[2..];
+        }
+
+        let value = try!(parse_real(body, radix, exactness == Some(true)).ok_or_else(invalid));
+        match exactness {
+            Some(true)  => value.to_exact().ok_or_else(invalid),
+            Some(false) => Ok(value.to_inexact()),
+            None        => Ok(value)
+        }
+    }
+
+    pub fn to_str_radix(&self, radix: u32) -> Result<String, String> {
+        match *self {
+            LispNum::Exact(ref n) if n.is_integer() => Ok(n.numer().to_str_radix(radix)),
+            LispNum::Exact(ref n) =>
+                Ok(format!("{}/{}", n.numer().to_str_radix(radix), n.denom().to_str_radix(radix))),
+            LispNum::Inexact(_) if radix == 10 => Ok(self.to_string()),
+            LispNum::Inexact(_) => Err(format!("Cannot write inexact {} in radix {}", self, radix))
+        }
+    }
+
+    pub fn compare(&self, other: &LispNum) -> Option<Ordering> {
+        match (self, other) {
+            (&LispNum::Exact(ref a), &LispNum::Exact(ref b)) => Some(a.cmp(b)),
+            _ => self.to_f64().partial_cmp(&other.to_f64())
+        }
+    }
+}
+
+macro_rules! contagious_op {
+    ($op:ident, $method:ident) => (
+        impl $op for LispNum {
+            type Output = LispNum;
+
+            fn $method(self, other: LispNum) -> LispNum {
+                match (self, other) {
+                    (LispNum::Exact(a), LispNum::Exact(b)) => LispNum::Exact(a.$method(b)),
+                    (a, b) => LispNum::Inexact(a.to_f64().$method(b.to_f64()))
+                }
+            }
+        }
+    );
+}
+
+contagious_op!(Add, add);
+contagious_op!(Sub, sub);
+contagious_op!(Mul, mul);
+contagious_op!(Div, div);
+
+impl Zero for LispNum {
+    fn zero() -> LispNum {
+        LispNum::Exact(BigRational::zero())
+    }
+
+    fn is_zero(&self) -> bool {
+        match *self {
+            LispNum::Exact(ref n) => n.is_zero(),
+            LispNum::Inexact(x) => x == 0.0
+        }
+    }
+}
+
+impl One for LispNum {
+    fn one() -> LispNum {
+        LispNum::Exact(BigRational::one())
+    }
+}
+
+impl PartialEq for LispNum {
+    fn eq(&self, other: &LispNum) -> bool {
+        match (self, other) {
+            (&LispNum::Exact(ref a), &LispNum::Exact(ref b)) => a == b,
+            (&LispNum::Inexact(a), &LispNum::Inexact(b)) => a == b || (a.is_nan() && b.is_nan()),
+            _ => false
+        }
+    }
+}
+
+impl Eq for LispNum {}
+
+impl FromStr for LispNum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LispNum, String> {
+        LispNum::from_str_radix(s, 10)
+    }
+}
+
+fn parse_real(s: &str, radix: u32, exact: bool) -> Option<LispNum> {
+    match s {
+        "+inf.0" => return Some(LispNum::Inexact(f64::INFINITY)),
+        "-inf.0" => return Some(LispNum::Inexact(f64::NEG_INFINITY)),
+        "+nan.0" | "-nan.0" => return Some(LispNum::Inexact(f64::NAN)),
+        _ => {}
+    }
+
+    if let Some(slash) = s.find('/') {
+        let (numer, denom) = (&s[..slash], &s[slash + 1..]);
+        if denom.starts_with(|c: char| c == '+' || c == '-') { return None; }
+
+        match (parse_integer(numer, radix), parse_integer(denom, radix)) {
+            (Some(n), Some(d)) => if d.is_zero() {
+                None
+            } else {
+                Some(LispNum::Exact(BigRational::new(n, d)))
+            },
+            _ => None
+        }
+    } else if radix == 10 && s.contains(|c: char| c == '.' || c == 'e' || c == 'E') {
+        parse_decimal(s, exact)
+    } else {
+        parse_integer(s, radix).map(LispNum::from_integer)
+    }
+}
+
+fn parse_integer(s: &str, radix: u32) -> Option<BigInt> {
+    let digits = s.trim_left_matches(|c: char| c == '+' || c == '-');
+
+    if digits.is_empty() || s.len() - digits.len() > 1 || !digits.chars().all(|c| c.is_digit(radix)) {
+        None
+    } else {
+        BigInt::from_str_radix(s, radix).ok()
+    }
+}
+
+fn parse_decimal(s: &str, exact: bool) -> Option<LispNum> {
+    let (mantissa, exponent) = match s.find(|c: char| c == 'e' || c == 'E') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None    => (s, "0")
+    };
+    let (sign, unsigned) = match mantissa.chars().next() {
+        Some('-') => ("-", &mantissa[1..]),
+        Some('+') => ("", &mantissa[1..]),
+        _         => ("", mantissa)
+    };
+    let mut parts = unsigned.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let fraction = parts.next().unwrap_or("");
+    let exponent = match parse_integer(exponent, 10).and_then(|e| e.to_i32()) {
+        Some(exponent) => exponent,
+        None => return None
+    };
+
+    if whole.len() + fraction.len() == 0 ||
+        !whole.chars().chain(fraction.chars()).all(|c| c.is_digit(10)) {
+        return None;
+    }
+
+    if exact {
+        let digits = format!("{}{}{}", sign, whole, fraction).parse::<BigInt>().unwrap();
+        let scale = exponent as i64 - fraction.len() as i64;
+        if scale.abs() > MAX_EXACT_EXPONENT {
+            return None;
+        }
+        let power = pow(BigInt::from(10), scale.abs() as usize);
+        if scale < 0 {
+            Some(LispNum::Exact(BigRational::new(digits, power)))
+        } else {
+            Some(LispNum::from_integer(digits * power))
+        }
+    } else {
+        s.parse::<f64>().ok().map(LispNum::Inexact)
+    }
+}
+
+impl fmt::Display for LispNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LispNum::Exact(ref n) => write!(f, "{}", n),
+            LispNum::Inexact(x) if x.is_nan() => write!(f, "+nan.0"),
+            LispNum::Inexact(x) if x.is_infinite() =>
+                write!(f, "{}inf.0", if x > 0.0 { "+" } else { "-" }),
+            LispNum::Inexact(x) => {
+                let string = x.to_string();
+                if string.contains(|c: char| c == '.' || c == 'e') {
+                    write!(f, "{}", string)
+                } else {
+                    write!(f, "{}.0", string)
+                }
+            }
+        }
+    }
+}
@@ -1,32 +1,71 @@
 use std::fmt;
 use std::default::Default;
-use num::rational;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 pub mod primitive_function;
 pub mod lisp_function;
+pub mod lisp_num;
+pub mod lisp_error;
+pub mod shared_value;
+pub mod syntax_rules;
+pub mod continuation;
 
 use super::lisp_environment::LispEnvironment;
+use super::reader::expression;
 pub use self::primitive_function::PrimitiveFunction;
 pub use self::lisp_function::LispFunction;
+pub use self::lisp_num::LispNum;
+pub use self::lisp_error::{LispError, ErrorKind};
+pub use self::shared_value::SharedValue;
+pub use self::syntax_rules::SyntaxRules;
+pub use self::continuation::Continuation;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LispValue {
     Atom(String),
-    List(Vec<LispValue>),
-    DottedList(Vec<LispValue>, Box<LispValue>),
+    List(Rc<Vec<LispValue>>),
+    DottedList(Rc<Vec<LispValue>>, Box<LispValue>),
     Number(LispNum),
     String(String),
+    Char(char),
+    Vector(Rc<RefCell<Vec<LispValue>>>),
+    Bytevector(Rc<RefCell<Vec<u8>>>),
+    Shared(SharedValue),
     PrimitiveFunction(PrimitiveFunction),
     Function(LispFunction),
+    Macro(SyntaxRules),
+    Continuation(Continuation),
+    Error(Rc<LispError>),
     Boolean(bool)
 }
 
-pub type LispNum = rational::BigRational;
-pub type LispResult = Result<LispValue, String>;
+pub type LispResult = Result<LispValue, LispError>;
 
 impl LispValue {
     pub fn quote(expression: LispValue) -> LispValue {
-        LispValue::List(vec![LispValue::Atom("quote".into()), expression])
+        LispValue::abbreviation("quote", expression)
+    }
+
+    pub fn abbreviation(keyword: &str, expression: LispValue) -> LispValue {
+        LispValue::list(vec![LispValue::Atom(keyword.into()), expression])
+    }
+
+    pub fn list(items: Vec<LispValue>) -> LispValue {
+        LispValue::List(Rc::new(items))
+    }
+
+    pub fn dotted_list(items: Vec<LispValue>, tail: LispValue) -> LispValue {
+        LispValue::DottedList(Rc::new(items), Box::new(tail))
+    }
+
+    pub fn vector(items: Vec<LispValue>) -> LispValue {
+        LispValue::Vector(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn bytevector(bytes: Vec<u8>) -> LispValue {
+        LispValue::Bytevector(Rc::new(RefCell::new(bytes)))
     }
 
     pub fn eval(&self) -> LispResult {
@@ -34,35 +73,213 @@ impl LispValue {
     }
 
     pub fn eval_in(&self, world: &LispEnvironment) -> (LispResult, LispEnvironment) {
+        world.eval(self)
+    }
+
+    pub fn identity(&self) -> Option<usize> {
+        match *self {
+            LispValue::List(ref xs) if !xs.is_empty() => Some(list_id(xs)),
+            LispValue::DottedList(ref xs, _) => Some(list_id(xs)),
+            _ => None
+        }
+    }
+
+    pub fn resolve(&self) -> LispValue {
         match *self {
-            LispValue::List(ref v) |
-            LispValue::DottedList(ref v, _) => world.call(v),
-            LispValue::Atom(ref v) => (world.get(v), world.clone()),
-            _ => (Ok(self.clone()), world.clone())
+            LispValue::Shared(ref v) => v.get().resolve(),
+            _ => self.clone()
         }
     }
 }
 
 impl fmt::Display for LispValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let string = match *self {
+        write!(f, "{}", self.write())
+    }
+}
+
+impl LispValue {
+    pub fn display(&self) -> String {
+        Printer::new(self, false, Labels::Cycles).print(self)
+    }
+
+    pub fn write(&self) -> String {
+        Printer::new(self, true, Labels::Cycles).print(self)
+    }
+
+    pub fn write_shared(&self) -> String {
+        Printer::new(self, true, Labels::Shared).print(self)
+    }
+
+    pub fn write_simple(&self) -> String {
+        Printer::new(self, true, Labels::None).print(self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Labels {
+    None,
+    Cycles,
+    Shared
+}
+
+struct Printer {
+    readable: bool,
+    labels: HashMap<usize, Option<usize>>,
+    next_label: usize
+}
+
+impl Printer {
+    fn new(value: &LispValue, readable: bool, labels: Labels) -> Printer {
+        let mut printer = Printer { readable: readable, labels: HashMap::new(), next_label: 0 };
+        if labels != Labels::None {
+            printer.find_labels(value, labels, &mut HashSet::new(), &mut HashSet::new());
+        }
+        printer
+    }
+
+    fn find_labels(&mut self, value: &LispValue, labels: Labels,
+                   seen: &mut HashSet<usize>, open: &mut HashSet<usize>) {
+        match *value {
+            LispValue::List(ref xs) => if !xs.is_empty() && self.enter(list_id(xs), labels, seen, open) {
+                for x in xs.iter() {
+                    self.find_labels(x, labels, seen, open);
+                }
+                open.remove(&list_id(xs));
+            },
+            LispValue::DottedList(ref xs, ref tail) => if self.enter(list_id(xs), labels, seen, open) {
+                for x in xs.iter() {
+                    self.find_labels(x, labels, seen, open);
+                }
+                self.find_labels(tail, labels, seen, open);
+                open.remove(&list_id(xs));
+            },
+            LispValue::Vector(ref v) => if self.enter(vector_id(v), labels, seen, open) {
+                for x in v.borrow().iter() {
+                    self.find_labels(x, labels, seen, open);
+                }
+                open.remove(&vector_id(v));
+            },
+            LispValue::Shared(ref v) => if self.enter(v.id(), labels, seen, open) {
+                self.find_labels(&v.borrow(), labels, seen, open);
+                open.remove(&v.id());
+            },
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, id: usize, labels: Labels,
+             seen: &mut HashSet<usize>, open: &mut HashSet<usize>) -> bool {
+        if open.contains(&id) || (labels == Labels::Shared && seen.contains(&id)) {
+            self.labels.insert(id, None);
+        }
+        seen.insert(id) && open.insert(id)
+    }
+
+    fn labelled<F: FnOnce(&mut Printer) -> String>(&mut self, id: usize, print: F) -> String {
+        match self.labels.get(&id).cloned() {
+            Some(Some(label)) => format!("#{}#", label),
+            Some(None) => {
+                let label = self.next_label;
+                self.next_label += 1;
+                self.labels.insert(id, Some(label));
+                format!("#{}={}", label, print(self))
+            },
+            None => print(self)
+        }
+    }
+
+    fn print(&mut self, value: &LispValue) -> String {
+        let readable = self.readable;
+        match *value {
+            LispValue::Atom(ref x) if syntax_rules::is_alias(x) =>
+                self.print(&LispValue::Atom(syntax_rules::base_name(x).into())),
+            LispValue::Atom(ref x) if readable && !reads_as_symbol(x) => format!("|{}|", escape_symbol(x)),
             LispValue::Atom(ref x) => x.clone(),
-            LispValue::List(ref x) => format!("({})", format_list(x)),
-            LispValue::DottedList(ref x, ref y) => format!("({} . {})", format_list(x), y),
+            LispValue::List(ref x) =>
+                self.labelled(list_id(x), |printer| format!("({})", printer.print_list(x))),
+            LispValue::DottedList(ref x, ref y) =>
+                self.labelled(list_id(x), |printer| format!("({} . {})", printer.print_list(x), printer.print(y))),
             LispValue::Number(ref x) => x.to_string(),
-            LispValue::String(ref x) => format!("\"{}\"", x),
+            LispValue::String(ref x) if readable => format!("\"{}\"", escape_string(x)),
+            LispValue::String(ref x) => x.clone(),
+            LispValue::Char(x) if readable => format!("#\\{}", format_char(x)),
+            LispValue::Char(x) => x.to_string(),
+            LispValue::Vector(ref x) =>
+                self.labelled(vector_id(x), |printer| format!("#({})", printer.print_list(&x.borrow()))),
+            LispValue::Bytevector(ref x) => format!("#u8({})", x.borrow().iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")),
+            LispValue::Shared(ref x) => self.labelled(x.id(), |printer| printer.print(&x.borrow())),
             LispValue::PrimitiveFunction(ref f) => format!("<primitive function:({})>", f.arg_list()),
             LispValue::Function(ref f) => format!("<function:({})>", f.arg_list()),
+            LispValue::Macro(_) => "<macro>".into(),
+            LispValue::Continuation(_) => "<continuation>".into(),
+            LispValue::Error(ref e) => format!("<error: {}>", e),
             LispValue::Boolean(true) => "#t".into(),
             LispValue::Boolean(false) => "#f".into(),
-        };
-        write!(f, "{}", string)
+        }
+    }
+
+    fn print_list(&mut self, list: &[LispValue]) -> String {
+        list.iter()
+            .map(|i| self.print(i))
+            .collect::<Vec<_>>()
+            .join(" ")
     }
 }
 
-fn format_list(list: &[LispValue]) -> String {
-    list.iter()
-        .map(|i| i.to_string())
-        .collect::<Vec<_>>()
-        .join(" ")
+fn list_id(list: &Rc<Vec<LispValue>>) -> usize {
+    &**list as *const Vec<LispValue> as usize
+}
+
+fn vector_id(vector: &Rc<RefCell<Vec<LispValue>>>) -> usize {
+    &**vector as *const RefCell<Vec<LispValue>> as usize
+}
+
+fn reads_as_symbol(name: &str) -> bool {
+    match expression(name) {
+        Ok(LispValue::Atom(ref x)) => x == name,
+        _ => false
+    }
+}
+
+fn escape_symbol(name: &str) -> String {
+    name.chars().map(|c| match c {
+        '|'  => "\\|".into(),
+        '\\' => "\\\\".into(),
+        c if c.is_control() => format!("\\x{:x};", c as u32),
+        c => c.to_string()
+    }).collect()
+}
+
+fn escape_string(string: &str) -> String {
+    string.chars().map(|c| match c {
+        '"'    => "\\\"".into(),
+        '\\'   => "\\\\".into(),
+        '\x07' => "\\a".into(),
+        '\x08' => "\\b".into(),
+        '\t'   => "\\t".into(),
+        '\n'   => "\\n".into(),
+        '\r'   => "\\r".into(),
+        c if c.is_control() => format!("\\x{:x};", c as u32),
+        c => c.to_string()
+    }).collect()
+}
+
+fn format_char(c: char) -> String {
+    match c {
+        '\x07' => "alarm".into(),
+        '\x08' => "backspace".into(),
+        '\x7f' => "delete".into(),
+        '\x1b' => "escape".into(),
+        '\n'   => "newline".into(),
+        '\0'   => "null".into(),
+        '\r'   => "return".into(),
+        ' '    => "space".into(),
+        '\t'   => "tab".into(),
+        c if c.is_control() || c.is_whitespace() => format!("x{:x}", c as u32),
+        c => c.to_string()
+    }
 }
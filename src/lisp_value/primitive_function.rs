@@ -1,16 +1,25 @@
-use lisp_value::{LispValue, LispResult};
-use lisp_environment::LispEnvironment;
+use lisp_value::{LispValue, LispResult, LispError};
+use lisp_environment::Step;
 use std::rc::Rc;
 use std::fmt;
 
 pub type LispPrimitiveFunction = Rc<Fn(&[LispValue]) -> LispResult>;
 
+/// A primitive that tells the evaluator how to go on instead of giving a value.
+pub type LispControlFunction = Rc<Fn(&[LispValue]) -> Step>;
+
+#[derive(Clone)]
+enum Definition {
+    Value(LispPrimitiveFunction),
+    Control(LispControlFunction)
+}
+
 #[derive(Clone)]
 pub struct PrimitiveFunction {
     name: String,
     args: Vec<String>,
     varargs: Option<String>,
-    func: LispPrimitiveFunction
+    func: Definition
 }
 
 impl PrimitiveFunction {
@@ -20,7 +29,17 @@ impl PrimitiveFunction {
             name: name.to_string(),
             args: args.iter().map(|&a| a.into()).collect(),
             varargs: varargs,
-            func: function
+            func: Definition::Value(function)
+        }
+    }
+
+    pub fn control(name: &str, args: &[&str], varargs: Option<String>,
+                   function: LispControlFunction) -> PrimitiveFunction {
+        PrimitiveFunction {
+            name: name.to_string(),
+            args: args.iter().map(|&a| a.into()).collect(),
+            varargs: varargs,
+            func: Definition::Control(function)
         }
     }
 
@@ -34,21 +53,24 @@ impl PrimitiveFunction {
         args.join(", ")
     }
 
-    pub fn check_arity(&self, args: &[LispValue]) -> Result<Vec<LispValue>, String> {
+    pub fn check_arity(&self, args: &[LispValue]) -> Result<Vec<LispValue>, LispError> {
         let (required, given) = (self.args.len(), args.len());
 
         if given < required {
             let at_least = if self.varargs.is_some() { "at least " } else { "" };
-            Err(format!("Not enough args ({} for {}{})", given, at_least, required))
+            Err(format!("Not enough args ({} for {}{})", given, at_least, required).into())
         } else if given > required && self.varargs.is_none() {
-            Err(format!("Too many args ({} for {})", given, required))
+            Err(format!("Too many args ({} for {})", given, required).into())
         } else {
             Ok(args.to_vec())
         }
     }
 
-    pub fn call(&self, _src_env: &LispEnvironment, args: &[LispValue]) -> LispResult {
-        (self.func)(args)
+    pub fn enter(&self, args: &[LispValue]) -> Step {
+        match self.func {
+            Definition::Value(ref f) => Step::Done(f(args)),
+            Definition::Control(ref f) => f(args)
+        }
     }
 }
 
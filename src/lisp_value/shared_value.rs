@@ -0,0 +1,45 @@
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use lisp_value::LispValue;
+
+#[derive(Clone)]
+pub struct SharedValue(Rc<RefCell<LispValue>>);
+
+impl SharedValue {
+    pub fn new(value: LispValue) -> SharedValue {
+        SharedValue(Rc::new(RefCell::new(value)))
+    }
+
+    pub fn get(&self) -> LispValue {
+        self.0.borrow().clone()
+    }
+
+    pub fn borrow(&self) -> Ref<LispValue> {
+        self.0.borrow()
+    }
+
+    pub fn set(&self, value: LispValue) {
+        *self.0.borrow_mut() = value;
+    }
+
+    pub fn id(&self) -> usize {
+        &*self.0 as *const RefCell<LispValue> as usize
+    }
+}
+
+/// Shared values are only equal to themselves, as their contents may be circular.
+impl PartialEq for SharedValue {
+    fn eq(&self, other: &SharedValue) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for SharedValue {}
+
+impl fmt::Debug for SharedValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedValue({})", LispValue::Shared(self.clone()).write())
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+
+use lisp_value::{LispValue, LispResult, LispError};
+use lisp_environment::LispEnvironment;
+
+/// Separates an inserted identifier from its expansion's number, as in `tmp\u{1f}3`.
+const ALIAS_MARK: char = '\u{1f}';
+
+pub fn alias(name: &str, expansion: usize) -> String {
+    format!("{}{}{}", name, ALIAS_MARK, expansion)
+}
+
+pub fn is_alias(name: &str) -> bool {
+    name.contains(ALIAS_MARK)
+}
+
+/// Splits the outermost renaming off an alias.
+pub fn unalias(name: &str) -> Option<(&str, usize)> {
+    name.rfind(ALIAS_MARK).and_then(|i| {
+        name[i + ALIAS_MARK.len_utf8()..].parse().ok().map(|expansion| (&name[..i], expansion))
+    })
+}
+
+pub fn base_name(name: &str) -> &str {
+    name.split(ALIAS_MARK).next().unwrap()
+}
+
+pub fn strip_syntax(datum: &LispValue) -> LispValue {
+    if !contains_alias(datum) {
+        return datum.clone();
+    }
+
+    match *datum {
+        LispValue::Atom(ref name) => LispValue::Atom(base_name(name).into()),
+        LispValue::List(ref items) => LispValue::list(items.iter().map(strip_syntax).collect()),
+        LispValue::DottedList(ref items, ref tail) =>
+            LispValue::dotted_list(items.iter().map(strip_syntax).collect(), strip_syntax(tail)),
+        LispValue::Vector(ref items) => LispValue::vector(items.borrow().iter().map(strip_syntax).collect()),
+        _ => datum.clone()
+    }
+}
+
+fn contains_alias(datum: &LispValue) -> bool {
+    match *datum {
+        LispValue::Atom(ref name) => is_alias(name),
+        LispValue::List(ref items) => items.iter().any(contains_alias),
+        LispValue::DottedList(ref items, ref tail) => items.iter().any(contains_alias) || contains_alias(tail),
+        LispValue::Vector(ref items) => items.borrow().iter().any(contains_alias),
+        _ => false
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Binding {
+    One(LispValue),
+    Many(Vec<Binding>)
+}
+
+type Bindings = HashMap<String, Binding>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxRules {
+    ellipsis: String,
+    literals: Vec<String>,
+    rules: Vec<(LispValue, LispValue)>,
+    env: LispEnvironment
+}
+
+impl SyntaxRules {
+    pub fn new(env: &LispEnvironment, spec: &[LispValue]) -> Result<SyntaxRules, LispError> {
+        let (ellipsis, spec) = match *spec {
+            [LispValue::Atom(ref ellipsis), ref spec..] => (ellipsis.clone(), spec),
+            _ => ("...".into(), spec)
+        };
+        let (literals, rules) = match *spec {
+            [LispValue::List(ref literals), ref rules..] => (literals, rules),
+            _ => return Err("Bad 'syntax-rules'".into())
+        };
+
+        let literals: Vec<String> = try!(literals.iter().map(|literal| match *literal {
+            LispValue::Atom(ref name) => Ok(name.clone()),
+            _ => Err(LispError::from(format!("Invalid literal: {}", literal)))
+        }).collect());
+        let rules: Vec<(LispValue, LispValue)> = try!(rules.iter().map(|rule| match *rule {
+            LispValue::List(ref rule) => match rule[..] {
+                [ref pattern @ LispValue::List(_), ref template] |
+                [ref pattern @ LispValue::DottedList(..), ref template] => Ok((pattern.clone(), template.clone())),
+                _ => Err(LispError::from(format!("Invalid syntax rule: {}", LispValue::List(rule.clone()))))
+            },
+            _ => Err(format!("Invalid syntax rule: {}", rule).into())
+        }).collect());
+
+        Ok(SyntaxRules { ellipsis: ellipsis, literals: literals, rules: rules, env: env.clone() })
+    }
+
+    /// Rewrites the macro use `form`, giving the expansion and the scope to evaluate it in.
+    pub fn expand(&self, form: &[LispValue], env: &LispEnvironment)
+                  -> Result<(LispValue, LispEnvironment), LispError> {
+        let nil = LispValue::list(vec![]);
+        let operands = if form.is_empty() { form } else { &form[1..] };
+
+        for &(ref pattern, ref template) in &self.rules {
+            let mut bindings = HashMap::new();
+            let matched = match *pattern {
+                LispValue::List(ref patterns) if !patterns.is_empty() =>
+                    self.match_sequence(&patterns[1..], None, operands, &nil, env, &mut bindings),
+                LispValue::DottedList(ref patterns, ref tail) if !patterns.is_empty() =>
+                    self.match_sequence(&patterns[1..], Some(tail), operands, &nil, env, &mut bindings),
+                _ => false
+            };
+
+            if matched {
+                let (expansion, scope) = env.begin_expansion(&self.env);
+                return self.instantiate(template, &bindings, expansion, false).map(|form| (form, scope));
+            }
+        }
+
+        Err(format!("No syntax rule matches {}", LispValue::list(form.to_vec())).into())
+    }
+
+    fn is_ellipsis(&self, value: &LispValue) -> bool {
+        match *value {
+            LispValue::Atom(ref name) => *name == self.ellipsis || base_name(name) == self.ellipsis,
+            _ => false
+        }
+    }
+
+    fn match_pattern(&self, pattern: &LispValue, input: &LispValue,
+                     env: &LispEnvironment, bindings: &mut Bindings) -> bool {
+        match *pattern {
+            LispValue::Atom(ref name) if base_name(name) == "_" => true,
+            LispValue::Atom(ref name) if self.literals.contains(name) => match *input {
+                LispValue::Atom(ref input) => self.env.denotation(name) == env.denotation(input),
+                _ => false
+            },
+            LispValue::Atom(ref name) => {
+                bindings.insert(name.clone(), Binding::One(input.clone()));
+                true
+            },
+            LispValue::List(ref patterns) => match list_parts(input) {
+                Some((items, tail)) => self.match_sequence(patterns, None, &items, &tail, env, bindings),
+                None => false
+            },
+            LispValue::DottedList(ref patterns, ref rest) => match list_parts(input) {
+                Some((items, tail)) => self.match_sequence(patterns, Some(rest), &items, &tail, env, bindings),
+                None => false
+            },
+            LispValue::Vector(ref patterns) => match *input {
+                LispValue::Vector(ref items) => {
+                    let nil = LispValue::list(vec![]);
+                    self.match_sequence(&patterns.borrow(), None, &items.borrow(), &nil, env, bindings)
+                },
+                _ => false
+            },
+            _ => *pattern == *input
+        }
+    }
+
+    fn match_sequence(&self, patterns: &[LispValue], rest: Option<&LispValue>,
+                      items: &[LispValue], tail: &LispValue,
+                      env: &LispEnvironment, bindings: &mut Bindings) -> bool {
+        let (before, repeated, after) = match patterns.iter().position(|p| self.is_ellipsis(p)) {
+            Some(0) => return false,
+            Some(i) => (&patterns[..i - 1], Some(&patterns[i - 1]), &patterns[i + 1..]),
+            None => (patterns, None, &patterns[..0])
+        };
+        let fixed = before.len() + after.len();
+        if items.len() < fixed {
+            return false;
+        }
+
+        let count = if repeated.is_some() { items.len() - fixed } else { 0 };
+        let (leading, remaining) = items.split_at(before.len());
+        let (repetitions, remaining) = remaining.split_at(count);
+
+        if !before.iter().zip(leading).all(|(p, item)| self.match_pattern(p, item, env, bindings)) {
+            return false;
+        }
+
+        if let Some(repeated) = repeated {
+            let mut matches = vec![];
+            for item in repetitions {
+                let mut repetition = HashMap::new();
+                if !self.match_pattern(repeated, item, env, &mut repetition) {
+                    return false;
+                }
+                matches.push(repetition);
+            }
+            for var in self.pattern_variables(repeated) {
+                let values = matches.iter_mut().map(|m| m.remove(&var).unwrap()).collect();
+                bindings.insert(var, Binding::Many(values));
+            }
+        }
+
+        let (trailing, remaining) = remaining.split_at(after.len());
+        if !after.iter().zip(trailing).all(|(p, item)| self.match_pattern(p, item, env, bindings)) {
+            return false;
+        }
+
+        match rest {
+            Some(rest) => self.match_pattern(rest, &join(remaining.to_vec(), tail.clone()), env, bindings),
+            None => remaining.is_empty() && *tail == LispValue::list(vec![])
+        }
+    }
+
+    fn pattern_variables(&self, pattern: &LispValue) -> Vec<String> {
+        match *pattern {
+            LispValue::Atom(ref name) =>
+                if self.is_ellipsis(pattern) || base_name(name) == "_" || self.literals.contains(name) {
+                    vec![]
+                } else {
+                    vec![name.clone()]
+                },
+            LispValue::List(ref patterns) =>
+                patterns.iter().flat_map(|p| self.pattern_variables(p)).collect(),
+            LispValue::DottedList(ref patterns, ref rest) => patterns.iter()
+                .chain(Some(&**rest))
+                .flat_map(|p| self.pattern_variables(p))
+                .collect(),
+            LispValue::Vector(ref patterns) =>
+                patterns.borrow().iter().flat_map(|p| self.pattern_variables(p)).collect(),
+            _ => vec![]
+        }
+    }
+
+    /// Fills in `template`, where an `escaped` ellipsis stands for itself.
+    fn instantiate(&self, template: &LispValue, bindings: &Bindings,
+                   expansion: usize, escaped: bool) -> LispResult {
+        match *template {
+            LispValue::Atom(ref name) => match bindings.get(name) {
+                Some(&Binding::One(ref value)) => Ok(value.clone()),
+                Some(&Binding::Many(_)) =>
+                    Err(format!("Pattern variable used without ellipsis: {}", base_name(name)).into()),
+                None => Ok(LispValue::Atom(alias(name, expansion)))
+            },
+            LispValue::List(ref templates) => match templates[..] {
+                [ref ellipsis, ref template] if !escaped && self.is_ellipsis(ellipsis) =>
+                    self.instantiate(template, bindings, expansion, true),
+                _ => self.instantiate_sequence(templates, bindings, expansion, escaped).map(LispValue::list)
+            },
+            LispValue::DottedList(ref templates, ref tail) => {
+                let items = try!(self.instantiate_sequence(templates, bindings, expansion, escaped));
+                let tail = try!(self.instantiate(tail, bindings, expansion, escaped));
+                Ok(join(items, tail))
+            },
+            LispValue::Vector(ref templates) => {
+                let templates = templates.borrow();
+                self.instantiate_sequence(&templates, bindings, expansion, escaped).map(LispValue::vector)
+            },
+            _ => Ok(template.clone())
+        }
+    }
+
+    fn instantiate_sequence(&self, templates: &[LispValue], bindings: &Bindings,
+                            expansion: usize, escaped: bool) -> Result<Vec<LispValue>, LispError> {
+        let mut result = vec![];
+        let mut i = 0;
+
+        while i < templates.len() {
+            let depth = if escaped {
+                0
+            } else {
+                templates[i + 1..].iter().take_while(|t| self.is_ellipsis(t)).count()
+            };
+            if depth == 0 {
+                result.push(try!(self.instantiate(&templates[i], bindings, expansion, escaped)));
+            } else {
+                result.extend(try!(self.repeat(&templates[i], bindings, expansion, depth)));
+            }
+            i += 1 + depth;
+        }
+
+        Ok(result)
+    }
+
+    fn repeat(&self, template: &LispValue, bindings: &Bindings,
+              expansion: usize, depth: usize) -> Result<Vec<LispValue>, LispError> {
+        let mut vars = vec![];
+        template_variables(template, &mut vars);
+        let repeated: Vec<(&String, &Vec<Binding>)> = vars.iter()
+            .filter_map(|var| match bindings.get(var) {
+                Some(&Binding::Many(ref values)) => Some((var, values)),
+                _ => None
+            })
+            .collect();
+
+        let count = match repeated.first() {
+            Some(&(_, values)) => values.len(),
+            None => return Err(format!("No pattern variable to repeat in {}", strip_syntax(template)).into())
+        };
+        if repeated.iter().any(|&(_, values)| values.len() != count) {
+            return Err(format!("Mismatched repetitions in {}", strip_syntax(template)).into());
+        }
+
+        let mut result = vec![];
+        for i in 0..count {
+            let mut bindings = bindings.clone();
+            for &(var, values) in &repeated {
+                bindings.insert(var.clone(), values[i].clone());
+            }
+            if depth == 1 {
+                result.push(try!(self.instantiate(template, &bindings, expansion, false)));
+            } else {
+                result.extend(try!(self.repeat(template, &bindings, expansion, depth - 1)));
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+fn template_variables(template: &LispValue, vars: &mut Vec<String>) {
+    match *template {
+        LispValue::Atom(ref name) => vars.push(name.clone()),
+        LispValue::List(ref templates) => for t in templates.iter() { template_variables(t, vars) },
+        LispValue::DottedList(ref templates, ref tail) => {
+            for t in templates.iter() { template_variables(t, vars) }
+            template_variables(tail, vars);
+        },
+        LispValue::Vector(ref templates) => for t in templates.borrow().iter() { template_variables(t, vars) },
+        _ => {}
+    }
+}
+
+/// Splits a list into its elements and whatever ends it, unless it is circular.
+fn list_parts(value: &LispValue) -> Option<(Vec<LispValue>, LispValue)> {
+    let mut items = vec![];
+    let mut shared = vec![];
+    let mut value = value.clone();
+
+    loop {
+        value = match value {
+            LispValue::Shared(cell) => if shared.contains(&cell.id()) {
+                return None;
+            } else {
+                shared.push(cell.id());
+                cell.get()
+            },
+            LispValue::List(list) => {
+                items.extend(list.iter().cloned());
+                return Some((items, LispValue::list(vec![])));
+            },
+            LispValue::DottedList(list, tail) => {
+                items.extend(list.iter().cloned());
+                *tail
+            },
+            _ if items.is_empty() => return None,
+            tail => return Some((items, tail))
+        };
+    }
+}
+
+fn join(mut items: Vec<LispValue>, tail: LispValue) -> LispValue {
+    match tail {
+        LispValue::List(rest) => {
+            items.extend(rest.iter().cloned());
+            LispValue::list(items)
+        },
+        LispValue::DottedList(rest, tail) => {
+            items.extend(rest.iter().cloned());
+            LispValue::dotted_list(items, *tail)
+        },
+        _ if items.is_empty() => tail,
+        tail => LispValue::dotted_list(items, tail)
+    }
+}
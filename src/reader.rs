@@ -0,0 +1,276 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+
+use lisp_value::{LispValue, LispError, SharedValue};
+use scheme;
+use scheme::ParseError;
+
+pub type ReadResult<T> = Result<T, ReadError>;
+
+/// Why the parser rejects a `|symbol|` containing the mark of a macro alias.
+pub const ALIAS_IN_SYMBOL: &'static str = "symbol without U+001F";
+
+pub struct ReadState {
+    spans: RefCell<HashMap<usize, (LispValue, Span)>>,
+    open: RefCell<Vec<(usize, &'static str)>>,
+    labels: RefCell<HashMap<u64, LispValue>>,
+    pub fold_case: Cell<bool>
+}
+
+impl ReadState {
+    pub fn new() -> ReadState {
+        ReadState {
+            spans: RefCell::new(HashMap::new()),
+            open: RefCell::new(vec![]),
+            labels: RefCell::new(HashMap::new()),
+            fold_case: Cell::new(false)
+        }
+    }
+
+    pub fn fold(&self, name: &str) -> String {
+        if self.fold_case.get() { name.to_lowercase() } else { name.into() }
+    }
+
+    /// Notes where a list was read, keeping it so that its identity stays unique.
+    pub fn record(&self, start: usize, end: usize, value: &LispValue) {
+        if let Some(id) = value.identity() {
+            self.spans.borrow_mut().insert(id, (value.clone(), Span { start: start, end: end }));
+        }
+    }
+
+    /// Notes that a `what` begins at `start`, until `close` sees its end.
+    pub fn open(&self, start: usize, what: &'static str) -> usize {
+        self.open.borrow_mut().push((start, what));
+        start
+    }
+
+    pub fn close(&self, start: usize) {
+        self.open.borrow_mut().retain(|&(open, _)| open != start);
+    }
+
+    pub fn define_label(&self, label: u64) -> SharedValue {
+        let cell = SharedValue::new(LispValue::Boolean(false));
+        self.labels.borrow_mut().insert(label, LispValue::Shared(cell.clone()));
+        cell
+    }
+
+    /// Finishes reading a labelled datum, keeping lists behind the label's cell.
+    pub fn complete_label(&self, label: u64, cell: SharedValue, datum: LispValue)
+                          -> Result<LispValue, &'static str> {
+        if datum == LispValue::Shared(cell.clone()) {
+            return Err("datum other than its own label");
+        }
+
+        let value = match datum {
+            LispValue::List(_) | LispValue::DottedList(..) => LispValue::Shared(cell.clone()),
+            _ => datum.clone()
+        };
+        cell.set(datum);
+        self.labels.borrow_mut().insert(label, value.clone());
+        Ok(value)
+    }
+
+    pub fn label(&self, label: u64) -> Option<LispValue> {
+        self.labels.borrow().get(&label).cloned()
+    }
+
+    /// Forgets the labels of a finished top-level datum; they are local to it.
+    pub fn clear_labels(&self) {
+        self.labels.borrow_mut().clear();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+#[derive(Clone, Debug)]
+pub struct SourceMap {
+    name: String,
+    source: String,
+    spans: HashMap<usize, (LispValue, Span)>
+}
+
+impl SourceMap {
+    pub fn locate(&self, form: &LispValue) -> Option<Span> {
+        form.identity().and_then(|id| self.spans.get(&id)).map(|&(_, span)| span)
+    }
+
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        position(&self.source, offset)
+    }
+
+    pub fn report(&self, error: &LispError) -> String {
+        let span = match error.span {
+            Some(span) => span,
+            None => return format!("{}: {}", self.name, error)
+        };
+        let (line, column) = self.position(span.start);
+        let line_start = self.source[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let text = self.source[line_start..].lines().next().unwrap_or("");
+        let indent: String = text.chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self.source[span.start..span.end].lines().next()
+            .map_or(1, |first| first.chars().count().max(1));
+
+        format!("{}:{}:{}: {}\n{}\n{}{}",
+                self.name, line, column, error, text, indent, "^".repeat(width))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub excerpt: String
+}
+
+impl ReadError {
+    fn new(input: &str, offset: usize, message: String) -> ReadError {
+        let (line, column) = position(input, offset);
+        let line_start = offset - input[..offset].rsplit('\n').next().unwrap_or("").len();
+
+        ReadError {
+            message: message,
+            line: line,
+            column: column,
+            excerpt: input[line_start..].lines().next().unwrap_or("").into()
+        }
+    }
+
+    fn diagnose(input: &str, state: &ReadState, error: ParseError) -> ReadError {
+        let offset = error.offset.min(input.len());
+        let innermost = state.open.borrow().iter()
+            .filter(|&&(start, _)| start < offset)
+            .max_by_key(|&&(start, _)| start)
+            .cloned();
+
+        if let Some((start, what)) = innermost {
+            if what == "`|` symbol" && error.expected.contains(ALIAS_IN_SYMBOL) {
+                return ReadError::new(input, start, "U+001F is reserved and cannot appear in a symbol".into());
+            }
+            if offset == input.len() {
+                let (line, column) = position(input, start);
+                let message = format!("unterminated {} starting at line {}, column {}", what, line, column);
+                return ReadError::new(input, start, message);
+            }
+            if what == "string" {
+                let escape_start = input[start..offset].rfind('\\').map_or(offset, |i| start + i);
+                let escape = input[escape_start..].chars().take(2).collect::<String>();
+                return ReadError::new(input, escape_start, format!("invalid escape `{}` in string", escape));
+            }
+        }
+
+        let token_start = input[..offset].char_indices().rev()
+            .find(|&(_, c)| is_delimiter(c))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let token = next_token(&input[token_start..]);
+        let previous = input[..token_start].trim_right();
+
+        if is_label_reference(token) {
+            ReadError::new(input, token_start, format!("undefined datum label `{}`", token))
+        } else if token.starts_with('#') && token.len() > 1 {
+            ReadError::new(input, token_start, format!("invalid `#` syntax `{}`", token))
+        } else if token == "." && input[token_start + 1..].trim_left().starts_with(')') {
+            let close = token_start + 1 + input[token_start + 1..].find(')').unwrap();
+            ReadError::new(input, close, "dotted list with no tail".into())
+        } else if token.starts_with(')') && previous.ends_with('.') &&
+            previous[..previous.len() - 1].ends_with(|c: char| c.is_whitespace() || c == '(') {
+            ReadError::new(input, token_start, "dotted list with no tail".into())
+        } else if offset == input.len() {
+            ReadError::new(input, offset, "unexpected end of input".into())
+        } else {
+            let token = if token.is_empty() { input[offset..].chars().take(1).collect() } else { token.to_string() };
+            ReadError::new(input, token_start, format!("unexpected `{}`", token))
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let indent: String = self.excerpt.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        write!(f, "{}:{}: {}\n{}\n{}^", self.line, self.column, self.message, self.excerpt, indent)
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')' || c == '"' || c == ';' || c == '|'
+}
+
+fn is_label_reference(token: &str) -> bool {
+    token.len() > 2 && token.starts_with('#') && token.ends_with('#') &&
+        token[1..token.len() - 1].chars().all(|c| c.is_digit(10))
+}
+
+fn next_token(input: &str) -> &str {
+    match input.char_indices().skip(1).find(|&(_, c)| is_delimiter(c)) {
+        Some((end, _)) => &input[..end],
+        None => input
+    }
+}
+
+fn position(input: &str, offset: usize) -> (usize, usize) {
+    let before = &input[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+
+    (line, column)
+}
+
+pub fn expression(input: &str) -> ReadResult<LispValue> {
+    let state = ReadState::new();
+    scheme::expression(input, &state)
+        .map_err(|err| ReadError::diagnose(input, &state, err))
+}
+
+pub fn program(input: &str) -> ReadResult<Vec<LispValue>> {
+    let state = ReadState::new();
+    scheme::program(input, &state)
+        .map_err(|err| ReadError::diagnose(input, &state, err))
+}
+
+pub fn program_ci(input: &str) -> ReadResult<Vec<LispValue>> {
+    let state = ReadState::new();
+    state.fold_case.set(true);
+    scheme::program(input, &state)
+        .map_err(|err| ReadError::diagnose(input, &state, err))
+}
+
+pub fn read_program(name: &str, input: &str) -> ReadResult<(Vec<LispValue>, SourceMap)> {
+    read_program_with(name, input, &Cell::new(false))
+}
+
+/// Reads `input` as more of a port whose `#!fold-case` state is `fold_case`.
+pub fn read_program_with(name: &str, input: &str, fold_case: &Cell<bool>)
+                         -> ReadResult<(Vec<LispValue>, SourceMap)> {
+    let state = ReadState::new();
+    state.fold_case.set(fold_case.get());
+    let forms = try!(scheme::program(input, &state)
+        .map_err(|err| ReadError::diagnose(input, &state, err)));
+    fold_case.set(state.fold_case.get());
+
+    let source_map = SourceMap {
+        name: name.into(),
+        source: input.into(),
+        spans: state.spans.into_inner()
+    };
+
+    Ok((forms, source_map))
+}
+
+#[allow(non_snake_case)]
+pub fn completeInput(input: &str) -> ReadResult<()> {
+    let state = ReadState::new();
+    scheme::completeInput(input, &state)
+        .map_err(|err| ReadError::diagnose(input, &state, err))
+}
@@ -1,11 +1,11 @@
-#![feature(box_syntax)]
-
 extern crate r7rs;
 
 mod shared;
 
+use std::f64;
+use std::str::FromStr;
 use shared::*;
-use r7rs::{LispEnvironment, expression};
+use r7rs::{LispEnvironment, LispNum, expression, read_program};
 use r7rs::LispValue::*;
 
 macro_rules! test_evaluation {
@@ -40,9 +40,9 @@ test_evaluation!(conditionals,
 );
 
 test_evaluation!(functions,
-    "(define (list . xs) xs)", "(list 1 2)" => List(vec![number(1), number(2)]),
+    "(define (list . xs) xs)", "(list 1 2)" => list(vec![number(1), number(2)]),
     "((if 2 - +) 2 1)" => number(1),
-    "(define (counter inc) (lambda (x) (define inc (+ x inc)) inc))",
+    "(define (counter inc) (lambda (x) (set! inc (+ x inc)) inc))",
         "(define my-count (counter 5))",
         "(my-count 4)",
         "(my-count 4)" => number(13),
@@ -51,3 +51,516 @@ test_evaluation!(functions,
     "(define (fib n) (if (<= n 2) 1 (+ (fib (- n 1)) (fib (- n 2)))))",
         "(fib 10)" => number(55)
 );
+
+test_evaluation!(characters,
+    "(char? #\\a)" => Boolean(true),
+    "(char? \"a\")" => Boolean(false),
+    "(char->integer #\\A)" => number(65),
+    "(integer->char 955)" => character('λ'),
+    "(char-upcase #\\a)" => character('A'),
+    "(char-upcase #\\ß)" => character('ß'),
+    "(char-downcase #\\İ)" => character('İ'),
+    "(char-alphabetic? #\\3)" => Boolean(false),
+    "(digit-value #\\7)" => number(7),
+    "(char<? #\\a #\\b #\\c)" => Boolean(true),
+    "(char=? #\\a #\\A)" => Boolean(false),
+    "(char-ci=? #\\a #\\A)" => Boolean(true)
+);
+
+test_evaluation!(vectors,
+    "#(1 2)" => vector(vec![number(1), number(2)]),
+    "(make-vector 2 'a)" => vector(vec![atom("a"), atom("a")]),
+    "(vector-length (make-vector 3))" => number(3),
+    "(vector-ref #(1 2 3) 1)" => number(2),
+    "(define v (vector 1 2 3))", "(vector-set! v 0 'x)", "v" => vector(vec![atom("x"), number(2), number(3)]),
+    "(vector->list #(1 2 3) 1)" => list(vec![number(2), number(3)]),
+    "(list->vector '(1 2))" => vector(vec![number(1), number(2)]),
+    "(define v (vector 1 2 3 4))", "(vector-fill! v 0 1 3)", "v" =>
+        vector(vec![number(1), number(0), number(0), number(4)]),
+    "(vector-copy #(1 2 3) 1 2)" => vector(vec![number(2)]),
+    "(define v (vector 1 2 3 4 5))", "(vector-copy! v 1 v 0 3)", "v" =>
+        vector(vec![number(1), number(1), number(2), number(3), number(5)]),
+    "(vector-append #(1) #() #(2 3))" => vector(vec![number(1), number(2), number(3)]),
+    "(vector-map + #(1 2 3) #(10 20))" => vector(vec![number(11), number(22)]),
+    "(define (square x) (* x x))", "(vector-map square #(1 2 3))" =>
+        vector(vec![number(1), number(4), number(9)])
+);
+
+#[test]
+fn vector_bounds() {
+    let world = LispEnvironment::default();
+    for input in &["(vector-ref #(1 2) 2)", "(vector-ref #(1 2) -1)", "(vector-set! #() 0 1)",
+                   "(vector-copy #(1 2) 2 1)", "(vector-copy! (make-vector 1) 0 #(1 2))"] {
+        assert!(expression(input).unwrap().eval_in(&world).0.is_err(), "{}", input);
+    }
+}
+
+test_evaluation!(bytevectors,
+    "#u8(1 2)" => bytevector(vec![1, 2]),
+    "(bytevector 1 2 3)" => bytevector(vec![1, 2, 3]),
+    "(make-bytevector 2 7)" => bytevector(vec![7, 7]),
+    "(bytevector-length #u8(1 2 3))" => number(3),
+    "(bytevector-u8-ref #u8(5 6 7) 2)" => number(7),
+    "(define bv (bytevector 1 2 3))", "(bytevector-u8-set! bv 1 255)", "bv" => bytevector(vec![1, 255, 3]),
+    "(bytevector-copy #u8(1 2 3) 1)" => bytevector(vec![2, 3]),
+    "(define bv (bytevector 1 2 3 4 5))", "(bytevector-copy! bv 0 #u8(9 8) 1)", "bv" =>
+        bytevector(vec![8, 2, 3, 4, 5]),
+    "(bytevector-append #u8(1) #u8(2 3))" => bytevector(vec![1, 2, 3]),
+    "(utf8->string #u8(206 187))" => string("λ"),
+    "(string->utf8 \"aλb\" 1 2)" => bytevector(vec![206, 187])
+);
+
+#[test]
+fn bytevector_errors() {
+    let world = LispEnvironment::default();
+    for input in &["(bytevector 256)", "(make-bytevector 2 -1)", "(bytevector-u8-set! #u8(1) 0 1/2)",
+                   "(bytevector-u8-ref #u8(1) 1)", "(utf8->string #u8(255))"] {
+        assert!(expression(input).unwrap().eval_in(&world).0.is_err(), "{}", input);
+    }
+}
+
+test_evaluation!(quasiquotation,
+    "`(1 ,(+ 1 1) 3)" => list(vec![number(1), number(2), number(3)]),
+    "(define xs '(2 3))", "`(1 ,@xs 4)" => list(vec![number(1), number(2), number(3), number(4)]),
+    "(define xs '(2 3))", "`(1 ,@xs . 4)" =>
+        dotted_list(vec![number(1), number(2), number(3)], number(4)),
+    "`(1 . ,(+ 1 1))" => dotted_list(vec![number(1)], number(2)),
+    "(define xs '(2 3))", "`(1 . ,xs)" => list(vec![number(1), number(2), number(3)]),
+    "(define xs '(2 3))", "`#(1 ,@xs)" => vector(vec![number(1), number(2), number(3)]),
+    "`(1 `(2 ,(3 ,(+ 1 3))))" => list(vec![number(1), list(vec![atom("quasiquote"), list(vec![
+        number(2),
+        list(vec![atom("unquote"), list(vec![number(3), number(4)])])
+    ])])])
+);
+
+test_evaluation!(inexact_numbers,
+    "(+ 1 .5)" => float(1.5),
+    "(* 1/2 4)" => number(2),
+    "(- 1.5 1/2)" => float(1.0),
+    "(/ 1 2.0)" => float(0.5),
+    "(/ 1 0.)" => float(f64::INFINITY),
+    "(= 2 2.0)" => Boolean(true),
+    "(< 1/3 .34 1)" => Boolean(true),
+    "(= +nan.0 +nan.0)" => Boolean(false),
+    "(exact 2.5)" => Number(LispNum::from_str("5/2").unwrap()),
+    "(inexact 1/4)" => float(0.25),
+    "(exact? 1/2)" => Boolean(true),
+    "(inexact? 1e2)" => Boolean(true),
+    "(nan? (- +inf.0 +inf.0))" => Boolean(true),
+    "(infinite? -inf.0)" => Boolean(true),
+    "(finite? 1e308)" => Boolean(true),
+    "(nan? #e1e400)" => Boolean(false),
+    "(infinite? #e1e400)" => Boolean(false),
+    "(inexact #e1e400)" => float(f64::INFINITY),
+    "(inexact #e-1e400)" => float(f64::NEG_INFINITY),
+    "(inexact #e1e-400)" => float(0.0),
+    "(inexact (/ #e1e400 #e1e399))" => float(10.0),
+    "(< 1e308 #e1e400)" => Boolean(true)
+);
+
+#[test]
+fn division_by_exact_zero() {
+    let world = LispEnvironment::default();
+    for input in &["(/ 0)", "(/ 1 0)", "(/ 1.5 0)", "(/ 0 0)"] {
+        assert!(expression(input).unwrap().eval_in(&world).0.is_err(), "{}", input);
+    }
+}
+
+test_evaluation!(number_conversion,
+    "(number->string 255 16)" => string("ff"),
+    "(number->string -5/3 2)" => string("-101/11"),
+    "(number->string 1.5)" => string("1.5"),
+    "(number->string 2.0)" => string("2.0"),
+    "(string->number \"ff\" 16)" => number(255),
+    "(string->number \"#b11\" 16)" => number(3),
+    "(string->number \"1e2\")" => float(100.0),
+    "(string->number \"abc\")" => Boolean(false),
+    "(string->number \"#b102\")" => Boolean(false),
+    "(string->number \"#x#x1\")" => Boolean(false),
+    "(string->number \"#e#i1\")" => Boolean(false),
+    "(string->number \"#e+inf.0\")" => Boolean(false),
+    "(string->number \"1/0\")" => Boolean(false),
+    "(string->number \"1/-2\")" => Boolean(false),
+    "(string->number \"1..2\")" => Boolean(false),
+    "(string->number (number->string 12345 8) 8)" => number(12345)
+);
+
+#[test]
+fn error_locations() {
+    let source = "(define x 1)\n(define (f y)\n  (if y))\n(f x)";
+    let (forms, source_map) = read_program("test.scm", source).unwrap();
+    let mut world = LispEnvironment::default();
+    let mut error = None;
+
+    for form in forms {
+        let (result, new_world) = world.eval_source(&form, &source_map);
+        world = new_world;
+        if let Err(err) = result { error = Some(err); }
+    }
+
+    let error = error.unwrap();
+    assert_eq!(error.message, "Bad 'if'");
+    assert_eq!(source_map.report(&error), "test.scm:3:3: Bad 'if'\n  (if y))\n  ^^^^^^");
+
+    let source = "(define (f) (/ 1 0))\n#;(/ 1 0) \"λλ\" (/ 1 0)";
+    let (forms, source_map) = read_program("test.scm", source).unwrap();
+    let error = forms.iter().map(|form| world.eval_source(form, &source_map).0).last().unwrap().unwrap_err();
+    assert_eq!(source_map.report(&error),
+               "test.scm:2:16: Cannot divide by zero.\n#;(/ 1 0) \"λλ\" (/ 1 0)\n               ^^^^^^^");
+
+    let source = "(define (f) (/ 1 0))\n(define (g) (/ 1 0))\n(g)";
+    let (forms, source_map) = read_program("test.scm", source).unwrap();
+    let error = forms.iter().map(|form| world.eval_source(form, &source_map).0).last().unwrap().unwrap_err();
+    assert!(source_map.report(&error).starts_with("test.scm:2:13: "));
+
+    let source = "(define-syntax divide (syntax-rules () ((_ a b) (/ a b))))\n(divide 1 0)";
+    let (forms, source_map) = read_program("test.scm", source).unwrap();
+    let error = forms.iter().map(|form| world.eval_source(form, &source_map).0).last().unwrap().unwrap_err();
+    assert!(source_map.report(&error).starts_with("test.scm:2:1: "));
+}
+
+#[test]
+fn include() {
+    use std::fs::File;
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join("r7rs-include-test.scm");
+    File::create(&path).unwrap()
+        .write_all(b"(define (Double x) (* x 2))\n(define Answer (Double 21))\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let world = LispEnvironment::default();
+    let (result, world) = expression(&format!("(include-ci {:?})", path)).unwrap().eval_in(&world);
+    assert_eq!(result, Ok(number(42)));
+    assert_eq!(expression("(double answer)").unwrap().eval_in(&world).0, Ok(number(84)));
+
+    let (result, world) = expression(&format!("(include {:?})", path)).unwrap().eval_in(&world);
+    assert_eq!(result, Ok(number(42)));
+    assert_eq!(expression("Answer").unwrap().eval_in(&world).0, Ok(number(42)));
+
+    assert!(expression("(include \"/nonexistent/file.scm\")").unwrap().eval().is_err());
+}
+
+test_evaluation!(tail_calls,
+    "(define (loop n) (if (= n 0) 'done (loop (- n 1))))", "(loop 100000)" => atom("done"),
+    "(define (even? n) (if (= n 0) #t (odd? (- n 1))))",
+    "(define (odd? n) (if (= n 0) #f (even? (- n 1))))",
+    "(even? 10001)" => Boolean(false),
+    "(define (count n acc) (define next (+ acc 1)) (if (= n 0) acc (count (- n 1) next)))",
+    "(count 10000 0)" => number(10000)
+);
+
+test_evaluation!(lexical_scope,
+    "(define x 'global)", "(define (get-x) x)", "(define (shadow x) (get-x))", "(shadow 'local)" => atom("global"),
+    "(define (make-counter)
+       (define count 0)
+       (vector (lambda () count) (lambda () (set! count (+ count 1)) count)))",
+    "(define counter (make-counter))",
+    "((vector-ref counter 1))", "((vector-ref counter 1))", "((vector-ref counter 0))" => number(2),
+    "(define n 1)", "(define (bump) (set! n (+ n 1)))", "(bump)", "(bump)", "n" => number(3),
+    "(define (adder n) (lambda (x) (+ x n)))", "(define add2 (adder 2))", "(define n 40)", "(add2 n)" => number(42),
+    "(define local 0)", "(define (f) (define local 1) local)", "(f)", "local" => number(0)
+);
+
+test_evaluation!(conditional_forms,
+    "(if #f #f)" => list(vec![]),
+    "(if 0 'yes)" => atom("yes"),
+    "(cond ((> 3 2) 'greater) ((< 3 2) 'less))" => atom("greater"),
+    "(cond ((> 3 3) 'greater) ((< 3 3) 'less) (else 'equal))" => atom("equal"),
+    "(cond ((+ 1 1) => (lambda (x) (* x 3))) (else #f))" => number(6),
+    "(cond (#f 1) ((* 2 2)))" => number(4),
+    "(cond (#f 1))" => list(vec![]),
+    "(case (* 2 3) ((2 3 5 7) 'prime) ((1 4 6 8 9) 'composite))" => atom("composite"),
+    "(case #\\x ((#\\a #\\e) 'vowel) (else => (lambda (c) (char->integer c))))" => number(120),
+    "(case 'b ((a) 1) ((b) => vector))" => vector(vec![atom("b")]),
+    "(case 4 ((1) 'one))" => list(vec![]),
+    "(and 1 2 'c)" => atom("c"),
+    "(and 1 #f (car '()))" => Boolean(false),
+    "(and)" => Boolean(true),
+    "(or #f 2 (car '()))" => number(2),
+    "(or #f #f)" => Boolean(false),
+    "(or)" => Boolean(false),
+    "(when (> 1 0) 'a 'b)" => atom("b"),
+    "(when #f 'a)" => list(vec![]),
+    "(unless #f 'a 'b)" => atom("b"),
+    "(unless 1 'a)" => list(vec![]),
+    "(define (loop n) (cond ((= n 0) 'done) (else (loop (- n 1)))))", "(loop 100000)" => atom("done"),
+    "(define (loop n) (and #t (or #f (when #t (unless #f (if (= n 0) 'done (loop (- n 1))))))))",
+    "(loop 100000)" => atom("done"),
+    "(define (loop n) (case n ((0) 'done) (else (loop (- n 1)))))", "(loop 100000)" => atom("done")
+);
+
+test_evaluation!(sequencing,
+    "(begin 1 2 3)" => number(3),
+    "(define x 1)", "(begin (set! x 5) (+ x 1))" => number(6),
+    "(begin (define a 1) (define (b) (+ a 1)))", "(b)" => number(2),
+    "(define (f) (begin (define y 2)) (* y 3))", "(f)" => number(6),
+    "(define (loop n) (begin 'ignored (if (= n 0) 'done (loop (- n 1)))))", "(loop 100000)" => atom("done")
+);
+
+test_evaluation!(iteration,
+    "(do ((vec (make-vector 5)) (i 0 (+ i 1))) ((= i 5) vec) (vector-set! vec i i))" =>
+        vector(vec![number(0), number(1), number(2), number(3), number(4)]),
+    "(do ((k 10) (sum 0 (+ sum k)) (i 0 (+ i 1))) ((= i 3) sum))" => number(30),
+    "(do ((i 0 (+ i 1)) (acc '() (cons i acc))) ((= i 3) 'ignored acc))" =>
+        list(vec![number(2), number(1), number(0)]),
+    "(do ((i 0 (+ i 1))) ((= i 100000)))" => list(vec![]),
+    "(define procs (make-vector 2))",
+    "(do ((i 0 (+ i 1))) ((= i 2)) (vector-set! procs i (lambda () i)))",
+    "((vector-ref procs 0))" => number(0)
+);
+
+test_evaluation!(macros,
+    "(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))",
+    "(define tmp 1)", "(define other 2)", "(swap! tmp other)", "(vector tmp other)" =>
+        vector(vec![number(2), number(1)]),
+    "(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))",
+    "(define t 5)", "(my-or #f t)" => number(5),
+    "(define-syntax my-if (syntax-rules () ((_ c a b) (cond (c a) (else b)))))",
+    "(let ((else #f)) (my-if #f 1 2))" => number(2),
+    "(define-syntax my-vector (syntax-rules () ((_ x ...) (vector x ...))))",
+    "(let ((vector +)) (my-vector 1 2))" => vector(vec![number(1), number(2)]),
+    "(define-syntax flatten (syntax-rules () ((_ (a b ...) ...) '(a ... (b ... ...)))))",
+    "(flatten (1 2 3) (4 5))" =>
+        list(vec![number(1), number(4), list(vec![number(2), number(3), number(5)])]),
+    "(define-syntax my-let* (syntax-rules ::: () ((_ () body :::) (let () body :::))
+       ((_ ((x v) rest :::) body :::) (let ((x v)) (my-let* (rest :::) body :::)))))",
+    "(my-let* ((a 1) (b (+ a 1))) (* b 10))" => number(20),
+    "(define-syntax arrow (syntax-rules (=>) ((_ a => b) (vector b a)) ((_ a b c) 'no)))",
+    "(arrow 1 => 2)" => vector(vec![number(2), number(1)]),
+    "(define-syntax arrow (syntax-rules (=>) ((_ a => b) (vector b a)) ((_ a b c) 'no)))",
+    "(let ((=> 0)) (arrow 1 => 2))" => atom("no"),
+    "(define-syntax dots (syntax-rules () ((_ a) '(a (... ...)))))", "(dots 1)" =>
+        list(vec![number(1), atom("...")]),
+    "(define-syntax tail (syntax-rules () ((_ a . rest) 'rest)))", "(tail 1 2 3)" =>
+        list(vec![number(2), number(3)]),
+    "(define-syntax sum (syntax-rules () ((_ #(a ...)) (+ a ...))))", "(sum #(1 2 3))" => number(6),
+    "(define-syntax define-getter (syntax-rules () ((_ name value)
+       (define-syntax name (syntax-rules () ((_) value))))))",
+    "(define-getter seven 7)", "(seven)" => number(7),
+    "(let-syntax ((foo (syntax-rules () ((_ x) (* x 2))))) (foo 21))" => number(42),
+    "(let ((x 'inner)) (let-syntax ((get (syntax-rules () ((_) x)))) (let ((x 'shadow)) (get))))" =>
+        atom("inner"),
+    "(letrec-syntax ((ev? (syntax-rules () ((_ n) (if (= n 0) #t (od? (- n 1))))))
+                     (od? (syntax-rules () ((_ n) (if (= n 0) #f (ev? (- n 1)))))))
+       (ev? 4))" => Boolean(true),
+    "(define-syntax while (syntax-rules () ((_ c body ...) (let lp () (when c body ... (lp))))))",
+    "(define i 0)", "(while (< i 100000) (set! i (+ i 1)))", "i" => number(100000),
+    "(define-syntax define-hidden (syntax-rules () ((_ name) (begin (define hidden 5) (define (name) hidden)))))",
+    "(define hidden 1)", "(define-hidden get)", "(define (f) (define-hidden g) (g))", "(vector (get) (f) hidden)" =>
+        vector(vec![number(5), number(5), number(1)])
+);
+
+test_evaluation!(macroexpansion,
+    "(define-syntax swap! (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))",
+    "(macroexpand-1 '(swap! tmp y))" => expression("(let ((tmp.0 tmp)) (set! tmp y) (set! y tmp.0))").unwrap(),
+    "(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))",
+    "(macroexpand '(my-or a b))" => expression("(let ((t.0 a)) (if t.0 t.0 (my-or b)))").unwrap(),
+    "(define-syntax first (syntax-rules () ((_ a b ...) a)))",
+    "(define-syntax second (syntax-rules () ((_ a b ...) (first b ...))))",
+    "(macroexpand-1 '(second 1 2 3))" => expression("(first 2 3)").unwrap(),
+    "(define-syntax first (syntax-rules () ((_ a b ...) a)))",
+    "(define-syntax second (syntax-rules () ((_ a b ...) (first b ...))))",
+    "(macroexpand '(second 1 2 3))" => number(2),
+    "(macroexpand '(+ 1 2))" => expression("(+ 1 2)").unwrap(),
+    "(define-syntax first (syntax-rules () ((_ a b ...) a)))",
+    "(vector-map macroexpand-1 #((first 1 2) (+ 1 2)))" => vector(vec![number(1), expression("(+ 1 2)").unwrap()]),
+    "(define-syntax first (syntax-rules () ((_ a b ...) a)))",
+    "(define form '(first 1 2))", "((lambda (expand) (expand form)) macroexpand)" => number(1)
+);
+
+#[test]
+fn full_expansion() {
+    let world = LispEnvironment::default();
+    for definition in &[
+        "(define-syntax while (syntax-rules () ((_ c body ...) (let lp () (when c body ... (lp))))))",
+        "(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))"
+    ] {
+        world.eval(&expression(definition).unwrap()).0.unwrap();
+    }
+
+    let expand = |input: &str| world.expand(&expression(input).unwrap()).unwrap();
+    assert_eq!(expand("(while (my-or a b) (set! a #f))"),
+               expression("(let lp.0 () (when (let ((t.1 a)) (if t.1 t.1 b)) (set! a #f) (lp.0)))").unwrap());
+    assert_eq!(expand("(lambda (while) (while 1))"), expression("(lambda (while) (while 1))").unwrap());
+    assert_eq!(expand("'(my-or 1 2)"), expression("'(my-or 1 2)").unwrap());
+    assert_eq!(expand("(let-syntax ((one (syntax-rules () ((_) 1)))) (+ (one) (my-or)))"),
+               expression("(let () (+ 1 #f))").unwrap());
+}
+
+#[test]
+fn macro_errors() {
+    let world = LispEnvironment::default();
+    for input in &[
+        "(define-syntax one (syntax-rules () ((_ x) x)))\n(one)",
+        "(define-syntax bad (syntax-rules () ((_ x ...) x)))\n(bad 1 2)",
+        "(define-syntax bad (syntax-rules () ((_ x) (x ...))))\n(bad 1)",
+        "(define-syntax bad (syntax-rules))",
+        "(define-syntax bad 5)",
+        "(quote)",
+        "(quote a b)"
+    ] {
+        let (forms, _) = read_program("test.scm", input).unwrap();
+        let result = forms.iter().map(|form| form.eval_in(&world).0).last().unwrap();
+        assert!(result.is_err(), "{}", input);
+    }
+}
+
+test_evaluation!(binding_forms,
+    "(let ((x 1) (y 2)) (+ x y))" => number(3),
+    "(define x 10)", "(let ((x 1) (y x)) y)" => number(10),
+    "(let* ((x 1) (y (+ x 1))) (* x y))" => number(2),
+    "(let* ((x 1) (x (+ x 1))) x)" => number(2),
+    "(let () 5)" => number(5),
+    "(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))
+              (odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))))
+       (even? 100))" => Boolean(true),
+    "(letrec* ((a 1) (b (+ a 1))) b)" => number(2),
+    "(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))" =>
+        list(vec![number(2), number(1), number(0)]),
+    "(let loop ((i 0)) (if (= i 10000) 'done (loop (+ i 1))))" => atom("done"),
+    "(define (f) (define a 1) (define (g) (* a 2)) (g))", "(f)" => number(2)
+);
+
+#[test]
+fn letrec_restrictions() {
+    let world = LispEnvironment::default();
+    for input in &[
+        "(letrec ((a 1) (b (+ a 1))) b)",
+        "(letrec* ((b (+ a 1)) (a 1)) b)",
+        "(define y 1)\n(define (f) (define x y) (define y 2) x)\n(f)",
+        "(let ((x 1) . y) x)",
+        "(let ((x)) x)",
+        "(let ((x 1)))",
+        "(let*)"
+    ] {
+        let (forms, _) = read_program("test.scm", input).unwrap();
+        let result = forms.iter().map(|form| form.eval_in(&world).0).last().unwrap();
+        assert!(result.is_err(), "{}", input);
+    }
+
+    let (forms, source_map) = read_program("test.scm", "(letrec ((a b) (b 1)) a)").unwrap();
+    let err = world.eval_source(&forms[0], &source_map).0.unwrap_err();
+    assert_eq!(err.message, "Variable used before its initialisation: 'b'");
+    assert!(source_map.report(&err).starts_with("test.scm:1:1: "));
+}
+
+test_evaluation!(continuations,
+    "(call/cc (lambda (k) (+ 1 (k 42))))" => number(42),
+    "(+ 1 (call-with-current-continuation (lambda (k) 2)))" => number(3),
+    "(call/cc (lambda (return)
+       (vector-for-each (lambda (x) (if (< x 0) (return x))) #(1 -2 3))
+       'none))" => number(-2),
+    "(define k #f)", "(define x (+ 100 (call/cc (lambda (c) (set! k c) 1))))", "(k 5)", "x" => number(105),
+    "(let ((i 0) (k #f))
+       (call/cc (lambda (c) (set! k c)))
+       (set! i (+ i 1))
+       (if (< i 5) (k #f) i))" => number(5),
+    "(let ((x (call/cc (lambda (k) k)))) (if (vector? x) x (x (vector 1 2))))" =>
+        vector(vec![number(1), number(2)]),
+    "(call/cc (lambda (k) (k)))" => list(vec![])
+);
+
+test_evaluation!(dynamic_wind,
+    "(define trace '())", "(define (note x) (set! trace (cons x trace)))",
+        "(dynamic-wind (lambda () (note 'before)) (lambda () (note 'during) 'result) (lambda () (note 'after)))" =>
+        atom("result"),
+    "(define trace '())", "(define (note x) (set! trace (cons x trace)))",
+        "(dynamic-wind (lambda () (note 'before)) (lambda () (note 'during)) (lambda () (note 'after)))",
+        "trace" => list(vec![atom("after"), atom("during"), atom("before")]),
+    "(define trace '())", "(define (note x) (set! trace (cons x trace)))",
+        "(call/cc (lambda (k)
+           (dynamic-wind (lambda () (note 'in)) (lambda () (k 'escaped) (note 'unreached)) (lambda () (note 'out)))))",
+        "trace" => list(vec![atom("out"), atom("in")]),
+    "(define trace '())", "(define (note x) (set! trace (cons x trace)))",
+        "(define k #f)", "(define n 0)",
+        "(dynamic-wind (lambda () (note 'in))
+                       (lambda () (call/cc (lambda (c) (set! k c))) (set! n (+ n 1)))
+                       (lambda () (note 'out)))",
+        "(if (< n 2) (k #f))",
+        "trace" => list(vec![atom("out"), atom("in"), atom("out"), atom("in")]),
+    "(define trace '())", "(define (note x) (set! trace (cons x trace)))",
+        "(dynamic-wind (lambda () (note 'outer-in))
+                       (lambda () (call/cc (lambda (k)
+                         (dynamic-wind (lambda () (note 'inner-in)) (lambda () (k 0)) (lambda () (note 'inner-out))))))
+                       (lambda () (note 'outer-out)))",
+        "trace" => list(vec![atom("outer-out"), atom("inner-out"), atom("inner-in"), atom("outer-in")])
+);
+
+#[test]
+fn dynamic_wind_errors() {
+    let world = LispEnvironment::default();
+    let source = "(define trace '())\n\
+                  (dynamic-wind (lambda () #t) (lambda () (/ 1 0)) (lambda () (set! trace (cons 'after trace))))";
+    let (forms, source_map) = read_program("test.scm", source).unwrap();
+    assert!(forms[0].eval_in(&world).0.is_ok());
+
+    let err = world.eval_source(&forms[1], &source_map).0.unwrap_err();
+    assert_eq!(err.message, "Cannot divide by zero.");
+    assert!(source_map.report(&err).starts_with("test.scm:2:41: "));
+    assert_eq!(expression("trace").unwrap().eval_in(&world).0, Ok(list(vec![atom("after")])));
+}
+
+test_evaluation!(exceptions,
+    "(guard (e (#t (error-object-message e))) (/ 1 0))" => string("Cannot divide by zero."),
+    "(guard (e (#t 0)) (+ 1 2))" => number(3),
+    "(guard (e ((vector? e) 'vector) ((char? e) e)) (raise #\\a))" => character('a'),
+    "(guard (e ((char? e) => (lambda (x) x))) (raise #\\a))" => Boolean(true),
+    "(guard (e (else 'caught)) (raise 'oops))" => atom("caught"),
+    "(guard (outer (else (vector 'outer outer)))
+       (guard (inner ((char? inner) 'inner))
+         (raise 'oops)))" => vector(vec![atom("outer"), atom("oops")]),
+    "(guard (e ((error-object? e) (vector (error-object-message e) (error-object-irritants e))))
+       (error \"Bad thing:\" 1 2))" =>
+        vector(vec![string("Bad thing:"), list(vec![number(1), number(2)])]),
+    "(guard (e (#t (error-object? e))) (raise 'x))" => Boolean(false),
+    "(guard (e (#t (vector (error-object? e) (read-error? e) (file-error? e))))
+       (include \"no/such/file.scm\"))" => vector(vec![Boolean(true), Boolean(false), Boolean(true)]),
+    "(with-exception-handler (lambda (c) 42) (lambda () (+ (raise-continuable 'oops) 1)))" => number(43),
+    "(with-exception-handler
+       (lambda (c) (if (char? c) 1 (raise-continuable 2)))
+       (lambda () (with-exception-handler (lambda (c) (+ (raise-continuable #\\a) 10)) (lambda () (raise-continuable 'x)))))" =>
+        number(11),
+    "(call/cc (lambda (k)
+       (with-exception-handler (lambda (e) (k (vector 'handled e))) (lambda () (raise 'boom)))))" =>
+        vector(vec![atom("handled"), atom("boom")]),
+    "(define trace '())",
+        "(guard (e (#t (set! trace (cons 'handled trace))))
+           (dynamic-wind (lambda () #t) (lambda () (raise 'x)) (lambda () (set! trace (cons 'after trace)))))",
+        "trace" => list(vec![atom("handled"), atom("after")])
+);
+
+#[test]
+fn uncaught_exceptions() {
+    let world = LispEnvironment::default();
+    let error = |input: &str| {
+        let (forms, source_map) = read_program("test.scm", input).unwrap();
+        let err = world.eval_source(&forms[0], &source_map).0.unwrap_err();
+        (err.message.clone(), source_map.report(&err))
+    };
+
+    assert_eq!(error("(raise 'oops)").0, "Uncaught exception: oops");
+    assert_eq!(error("(guard (e ((char? e) 'c)) (raise 'oops))").0, "Uncaught exception: oops");
+    assert!(error("(with-exception-handler (lambda (e) 0) (lambda () (raise 'oops)))").0
+        .starts_with("Handler returned from non-continuable exception"));
+    assert_eq!(error("(error \"Bad thing:\" 1 \"two\")").1,
+               "test.scm:1:1: Bad thing: 1 \"two\"\n(error \"Bad thing:\" 1 \"two\")\n^^^^^^^^^^^^^^^^^^^^^^^^^^^^");
+}
+
+test_evaluation!(datum_labels,
+    "(list->vector '#0=(a b))" => vector(vec![atom("a"), atom("b")]),
+    "(vector-length '#0=#(a #0#))" => number(2),
+    "(vector-ref (vector-ref '#0=#(a #0#) 1) 0)" => atom("a"),
+    "(cons 1 '#0=(2 3))" => list(vec![number(1), number(2), number(3)]),
+    "`(1 ,@'#0=(2 3))" => list(vec![number(1), number(2), number(3)]),
+    "(define-syntax second (syntax-rules () ((_ (a b)) 'b)))", "(second #0=(x y))" => atom("y")
+);
+
+#[test]
+fn shared_structure() {
+    let world = LispEnvironment::default();
+    let (_, world) = expression("(define x '(a))").unwrap().eval_in(&world);
+    let value = expression("(vector x x '(a))").unwrap().eval_in(&world).0.unwrap();
+    assert_eq!(value.write_shared(), "#(#0=(a) #0# (a))");
+    assert_eq!(value.write(), "#((a) (a) (a))");
+}
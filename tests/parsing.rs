@@ -1,11 +1,12 @@
-#![feature(box_syntax)]
-
 extern crate r7rs;
 
 mod shared;
 
+use std::cell::Cell;
+use std::f64;
+use std::str::FromStr;
 use shared::*;
-use r7rs::expression;
+use r7rs::{LispNum, expression, program, read_program_with, completeInput};
 use r7rs::LispValue::*;
 
 macro_rules! test_parsing {
@@ -18,40 +19,304 @@ macro_rules! test_parsing {
 }
 
 test_parsing!(atoms,
-    "foo" => atom("foo")
+    "foo" => atom("foo"),
+    "list->vector" => atom("list->vector"),
+    "<=?" => atom("<=?"),
+    "+" => atom("+"),
+    "-" => atom("-"),
+    "..." => atom("..."),
+    "+.foo" => atom("+.foo"),
+    "->x" => atom("->x"),
+    "-@x" => atom("-@x"),
+    "λ" => atom("λ"),
+    "café" => atom("café"),
+    "|hello world|" => atom("hello world"),
+    "||" => atom(""),
+    r"|a\|b\x41;\n|" => atom("a|bA\n"),
+    "(|foo| bar)" => list(vec![atom("foo"), atom("bar")])
 );
 
+#[test]
+fn invalid_atoms() {
+    for input in &["#foo", "a|b", "|unterminated", "."] {
+        assert!(expression(input).is_err(), "{}", input);
+    }
+}
+
+#[test]
+fn atoms_round_trip() {
+    for name in &["foo", "hello world", "", "1", "+1", "a|b", "a;b", "#t", "λ", "..."] {
+        assert_eq!(expression(&atom(name).to_string()), Ok(atom(name)));
+    }
+    assert_eq!(atom("foo").to_string(), "foo");
+    assert_eq!(atom("hello world").to_string(), "|hello world|");
+    assert_eq!(atom("hello world").display(), "hello world");
+}
+
 test_parsing!(quoting,
-    "'foo" => List(vec![atom("quote"), atom("foo")]),
-    "(foo 'bar)" => List(vec![atom("foo"), List(vec![atom("quote"), atom("bar")])])
+    "'foo" => list(vec![atom("quote"), atom("foo")]),
+    "(foo 'bar)" => list(vec![atom("foo"), list(vec![atom("quote"), atom("bar")])])
 );
 
 test_parsing!(lists,
-    "(foo bar baz)" => List(vec![atom("foo"), atom("bar"), atom("baz")])
+    "(foo bar baz)" => list(vec![atom("foo"), atom("bar"), atom("baz")])
 );
 
 test_parsing!(dotted_lists,
-    "(foo bar . baz)" => DottedList(vec![atom("foo"), atom("bar")], box atom("baz"))
+    "(foo bar . baz)" => dotted_list(vec![atom("foo"), atom("bar")], atom("baz")),
+    "(foo .\"bar\")" => dotted_list(vec![atom("foo")], string("bar")),
+    "(foo .bar)" => list(vec![atom("foo"), atom(".bar")])
+);
+
+test_parsing!(whitespace,
+    "\t(foo\tbar)\t" => list(vec![atom("foo"), atom("bar")]),
+    "(foo\r\n\x0cbar\x0b)" => list(vec![atom("foo"), atom("bar")]),
+    "(foo\t.\tbar)" => dotted_list(vec![atom("foo")], atom("bar")),
+    "(foo\n.\nbar)" => dotted_list(vec![atom("foo")], atom("bar")),
+    "(foo\u{a0}bar)" => list(vec![atom("foo"), atom("bar")]),
+    "'\tfoo" => list(vec![atom("quote"), atom("foo")]),
+    "`(a ,\tb ,@ c)" => list(vec![atom("quasiquote"), list(vec![
+        atom("a"),
+        list(vec![atom("unquote"), atom("b")]),
+        list(vec![atom("unquote-splicing"), atom("c")])
+    ])]),
+    "((a)(b)\"c\")" => list(vec![list(vec![atom("a")]), list(vec![atom("b")]), string("c")]),
+    "#(\t1\t2\t)" => vector(vec![number(1), number(2)]),
+    "#u8(\t1\x0c2)" => bytevector(vec![1, 2]),
+    "(a ; comment\r b)" => list(vec![atom("a"), atom("b")])
 );
 
+#[test]
+fn tokens_need_delimiters() {
+    for input in &["(#tfoo)", "(1a)", "(#\\ab)", "(. a)", "(a . b c)"] {
+        assert!(expression(input).is_err(), "{}", input);
+    }
+}
+
 test_parsing!(numbers,
     "13" => number(13),
     "-6" => number(-6),
-    "4.0" => number(4),
-    "-.0" => number(0),
+    "4.0" => float(4.0),
+    "-.0" => float(0.0),
     "-9/3" => number(-3),
     "+320/4" => number(80),
-    "+6.0" => number(6)
+    "+6.0" => float(6.0),
+    "1e3" => float(1000.0),
+    "1.5E-1" => float(0.15),
+    "+inf.0" => float(f64::INFINITY),
+    "-inf.0" => float(f64::NEG_INFINITY),
+    "+nan.0" => float(f64::NAN)
 );
 
 test_parsing!(strings,
     "\"hello world\"" => string("hello world"),
-    "\"foo\nbar\"" => string("foo\nbar")
+    "\"foo\nbar\"" => string("foo\nbar"),
+    r#""say \"hi\"\\""# => string("say \"hi\"\\"),
+    r#""\a\b\t\n\r\|""# => string("\x07\x08\t\n\r|"),
+    r#""\x41;\x3bb;""# => string("Aλ"),
+    "\"one \\  \n   two\"" => string("one two"),
+    "\"one \\\r\ntwo\"" => string("one two")
 );
 
+#[test]
+fn invalid_strings() {
+    for input in &[r#""\/""#, r#""\f""#, r#""\x41""#, r#""\xD800;""#, r#""\""#] {
+        assert!(expression(input).is_err(), "{}", input);
+    }
+}
+
+#[test]
+fn strings_round_trip() {
+    for value in &[string("plain"), string("\"quoted\" \\ back\tslash\n"), string("\x01\x07"),
+                   list(vec![string("a\"b"), character('"')])] {
+        assert_eq!(expression(&value.to_string()).as_ref(), Ok(value));
+    }
+    assert_eq!(string("a\"b").display(), "a\"b");
+}
+
 test_parsing!(booleans,
     "#t" => Boolean(true),
     "#true" => Boolean(true),
     "#f" => Boolean(false),
     "#false" => Boolean(false)
 );
+
+test_parsing!(comments,
+    "; leading\nfoo ; trailing" => atom("foo"),
+    "(foo ; bar\n baz)" => list(vec![atom("foo"), atom("baz")]),
+    "#| block #| nested |# |# foo" => atom("foo"),
+    "(foo #;(bar baz) qux)" => list(vec![atom("foo"), atom("qux")]),
+    "(foo #; bar)" => list(vec![atom("foo")]),
+    "(foo . #;bar baz)" => dotted_list(vec![atom("foo")], atom("baz"))
+);
+
+#[test]
+fn complete_input() {
+    assert!(completeInput("(foo ; )\n bar)").is_ok());
+    assert!(completeInput("#| ( |# foo").is_ok());
+    assert!(completeInput("\"(\"").is_ok());
+    assert!(completeInput("(foo ; )").is_err());
+    assert!(completeInput("#| foo").is_err());
+    assert!(completeInput("(foo #| ) |#").is_err());
+    assert!(completeInput("(|)|)").is_ok());
+    assert!(completeInput("(|)").is_err());
+    assert!(completeInput("(foo\t\x0c(bar)\t)").is_ok());
+    assert!(completeInput("(foo\t(bar)\t").is_err());
+}
+
+test_parsing!(characters,
+    "#\\a" => character('a'),
+    "#\\A" => character('A'),
+    "#\\(" => character('('),
+    "#\\space" => character(' '),
+    "#\\newline" => character('\n'),
+    "#\\x41" => character('A'),
+    "#\\x" => character('x'),
+    "(#\\a #\\b)" => list(vec![character('a'), character('b')])
+);
+
+test_parsing!(vectors,
+    "#()" => vector(vec![]),
+    "#(1 #(2) \"three\")" => vector(vec![number(1), vector(vec![number(2)]), string("three")]),
+    "(foo #( bar ))" => list(vec![atom("foo"), vector(vec![atom("bar")])])
+);
+
+test_parsing!(bytevectors,
+    "#u8()" => bytevector(vec![]),
+    "#u8(0 10 255)" => bytevector(vec![0, 10, 255]),
+    "(#u8( 1 ) #(2))" => list(vec![bytevector(vec![1]), vector(vec![number(2)])])
+);
+
+#[test]
+fn invalid_bytevectors() {
+    assert!(expression("#u8(256)").is_err());
+    assert!(expression("#u8(-1)").is_err());
+    assert!(expression("#u8(1/2)").is_err());
+    assert!(expression("#u8(a)").is_err());
+}
+
+test_parsing!(quasiquoting,
+    "`foo" => list(vec![atom("quasiquote"), atom("foo")]),
+    "`(a ,b ,@c)" => list(vec![atom("quasiquote"), list(vec![
+        atom("a"),
+        list(vec![atom("unquote"), atom("b")]),
+        list(vec![atom("unquote-splicing"), atom("c")])
+    ])])
+);
+
+test_parsing!(number_prefixes,
+    "#x1F" => number(31),
+    "#X-ff" => number(-255),
+    "#b1010" => number(10),
+    "#o777" => number(511),
+    "#d10" => number(10),
+    "#x1/A" => Number(LispNum::from_str("1/10").unwrap()),
+    "#e1.5" => Number(LispNum::from_str("3/2").unwrap()),
+    "#e.1" => Number(LispNum::from_str("1/10").unwrap()),
+    "#e1e2" => number(100),
+    "#i3/4" => float(0.75),
+    "#x#i10" => float(16.0),
+    "#i#x10" => float(16.0),
+    "#e#b101" => number(5)
+);
+
+#[test]
+fn exact_exponent_limits() {
+    assert!(expression("#e1e-2147483648").is_err());
+    assert!(expression("#e1e999999999").is_err());
+    assert!(expression("#e1e400").is_ok());
+}
+
+#[test]
+fn programs() {
+    assert_eq!(program(""), Ok(vec![]));
+    assert_eq!(program(" ; nothing here\n #| at all |# "), Ok(vec![]));
+    assert_eq!(program("(define x 1) (display x)"), Ok(vec![
+        list(vec![atom("define"), atom("x"), number(1)]),
+        list(vec![atom("display"), atom("x")])
+    ]));
+    assert_eq!(program("foo\n; comment\n'bar #;baz \"qux\"(quux)"), Ok(vec![
+        atom("foo"),
+        list(vec![atom("quote"), atom("bar")]),
+        string("qux"),
+        list(vec![atom("quux")])
+    ]));
+    assert!(program("(foo) (bar").is_err());
+}
+
+fn read_error(input: &str) -> std::string::String {
+    let err = program(input).unwrap_err();
+    format!("{}:{}: {}", err.line, err.column, err.message)
+}
+
+#[test]
+fn read_errors() {
+    assert_eq!(read_error("(foo)\n(bar\n  \"baz)"), "3:3: unterminated string starting at line 3, column 3");
+    assert_eq!(read_error("(foo\n  (bar)"), "1:1: unterminated list starting at line 1, column 1");
+    assert_eq!(read_error("foo #| bar"), "1:5: unterminated block comment starting at line 1, column 5");
+    assert_eq!(read_error("(foo))"), "1:6: unexpected `)`");
+    assert_eq!(read_error("(foo #q)"), "1:6: invalid `#` syntax `#q`");
+    assert_eq!(read_error("(a .)"), "1:5: dotted list with no tail");
+    assert_eq!(read_error("(a . )"), "1:6: dotted list with no tail");
+    assert_eq!(read_error("(a\t.\t)"), "1:6: dotted list with no tail");
+    assert_eq!(read_error("\"a\\qb\""), "1:3: invalid escape `\\q` in string");
+    assert_eq!(read_error("'"), "1:2: unexpected end of input");
+    assert_eq!(read_error("(a . b c)"), "1:8: unexpected `c`");
+    assert_eq!(read_error("\"\\λ\""), "1:2: invalid escape `\\λ` in string");
+    assert_eq!(read_error("(a\n #(λ"), "2:2: unterminated vector starting at line 2, column 2");
+    assert_eq!(read_error("(foo\u{a0}#q)"), "1:6: invalid `#` syntax `#q`");
+    assert_eq!(read_error("'|x\\x1f;1|"), "1:2: U+001F is reserved and cannot appear in a symbol");
+}
+
+#[test]
+fn read_error_display() {
+    let err = expression("(list 1\n\t(foo #q))").unwrap_err();
+    assert_eq!(err.to_string(), "2:7: invalid `#` syntax `#q`\n\t(foo #q))\n\t     ^");
+
+    let err = expression("(list 1\n  (foo #q))").unwrap_err();
+    assert_eq!(err.to_string(), "2:8: invalid `#` syntax `#q`\n  (foo #q))\n       ^");
+}
+
+#[test]
+fn datum_labels() {
+    let write = |input: &str| expression(input).unwrap().write();
+    assert_eq!(write("#0=(a b . #0#)"), "#0=(a b . #0#)");
+    assert_eq!(write("#0=(a #0#)"), "#0=(a #0#)");
+    assert_eq!(write("#1=#(a #1#)"), "#0=#(a #0#)");
+    assert_eq!(write("(#0=(a) #0#)"), "((a) (a))");
+    assert_eq!(write("#0=foo"), "foo");
+    assert_eq!(expression("(#0=foo #0#)"), Ok(list(vec![atom("foo"), atom("foo")])));
+    assert!(expression("#0#").is_err());
+    assert!(expression("#0=#0#").is_err());
+    assert_eq!(program("(#0#)").unwrap_err().message, "undefined datum label `#0#`");
+    assert_eq!(program("#0=(a) #0#").unwrap_err().message, "undefined datum label `#0#`");
+}
+
+#[test]
+fn write_shared_and_simple() {
+    let shared = expression("(#0=(a) #0# #1=#(b) #1#)").unwrap();
+    assert_eq!(shared.write_shared(), "(#0=(a) #0# #1=#(b) #1#)");
+    assert_eq!(shared.write(), "((a) (a) #(b) #(b))");
+    assert_eq!(shared.write_simple(), "((a) (a) #(b) #(b))");
+
+    let circular = expression("#0=(a . #0#)").unwrap();
+    assert_eq!(circular.write_shared(), "#0=(a . #0#)");
+    assert_eq!(circular.display(), "#0=(a . #0#)");
+    assert_eq!(circular.to_string(), "#0=(a . #0#)");
+}
+
+#[test]
+fn fold_case_directives() {
+    assert_eq!(program("Foo #!fold-case Foo #\\SPACE |Foo| #!no-fold-case Foo"), Ok(vec![
+        atom("Foo"), atom("foo"), character(' '), atom("Foo"), atom("Foo")
+    ]));
+    assert_eq!(program("#!fold-case #\\A #\\Newline"), Ok(vec![character('A'), character('\n')]));
+    assert!(expression("#\\SPACE").is_err());
+    assert!(expression("#!fold-cases foo").is_err());
+
+    let fold_case = Cell::new(false);
+    assert_eq!(read_program_with("<repl>", "#!fold-case", &fold_case).unwrap().0, vec![]);
+    assert_eq!(read_program_with("<repl>", "Foo", &fold_case).unwrap().0, vec![atom("foo")]);
+    assert_eq!(read_program_with("<repl>", "#!no-fold-case Foo", &fold_case).unwrap().0, vec![atom("Foo")]);
+    assert_eq!(read_program_with("<repl>", "Foo", &fold_case).unwrap().0, vec![atom("Foo")]);
+}
@@ -15,3 +15,27 @@ pub fn atom(ident: &str) -> LispValue {
 pub fn string(string: &str) -> LispValue {
     String(string.into())
 }
+
+pub fn character(c: char) -> LispValue {
+    Char(c)
+}
+
+pub fn vector(items: Vec<LispValue>) -> LispValue {
+    LispValue::vector(items)
+}
+
+pub fn bytevector(bytes: Vec<u8>) -> LispValue {
+    LispValue::bytevector(bytes)
+}
+
+pub fn float(x: f64) -> LispValue {
+    Number(LispNum::Inexact(x))
+}
+
+pub fn list(items: Vec<LispValue>) -> LispValue {
+    LispValue::list(items)
+}
+
+pub fn dotted_list(items: Vec<LispValue>, tail: LispValue) -> LispValue {
+    LispValue::dotted_list(items, tail)
+}